@@ -0,0 +1,62 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::Full;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{debug, warn};
+use tokio::net::TcpListener;
+
+use crate::metrics::Metrics;
+
+/// How long to wait before accepting connections again after an error, like
+/// running out of file descriptors.
+pub const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// Serve the admin endpoints on `listener`.
+/// The admin server is kept separate from ingest traffic so it can be firewalled off.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Could not accept admin connection: {0}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let io = TokioIo::new(stream);
+        let metrics = metrics.clone();
+
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(
+                    io,
+                    service_fn(move |req: Request<Incoming>| handle_request(req, metrics.clone())),
+                )
+                .await
+            {
+                debug!("Error serving admin connection: {:?}", err);
+            }
+        });
+    }
+}
+
+async fn handle_request(
+    req: Request<Incoming>,
+    metrics: Arc<Metrics>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let res = match (req.method(), req.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Full::new(Bytes::from(metrics.render()))),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Full::new(Bytes::from("Not found"))),
+    };
+    Ok(res.unwrap())
+}
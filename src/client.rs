@@ -0,0 +1,124 @@
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Request, Response};
+use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+
+use crate::config::ClientConfig;
+use crate::metrics::{self, Metrics};
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 5_000;
+const DEFAULT_REQUEST_TIMEOUT: u64 = 30_000;
+const DEFAULT_POOL_IDLE_TIMEOUT: u64 = 90_000;
+
+#[derive(Debug)]
+pub enum ClientError {
+    Request(hyper_util::client::legacy::Error),
+    Body(hyper::Error),
+    Timeout,
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Request(e) => write!(f, "request failed: {e:?}"),
+            ClientError::Body(e) => write!(f, "could not read response body: {e}"),
+            ClientError::Timeout => write!(f, "request timed out"),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+/// A pooled HTTP client shared by all outbound requests.
+/// Cloning the client is cheap and clones share the same connection pool.
+#[derive(Debug, Clone)]
+pub struct OutboundClient {
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    request_timeout: Duration,
+    metrics: Arc<Metrics>,
+}
+
+impl OutboundClient {
+    /// Build a client from the `client` configuration section.
+    pub fn new(
+        config: &ClientConfig,
+        metrics: Arc<Metrics>,
+    ) -> Result<OutboundClient, native_tls::Error> {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_connect_timeout(Some(Duration::from_millis(
+            config.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        )));
+
+        let http2 = config.http2.unwrap_or(false);
+        let mut tls = native_tls::TlsConnector::builder();
+        if http2 {
+            // Offer HTTP/2 during the TLS handshake and fall back to HTTP/1.1
+            tls.request_alpns(&["h2", "http/1.1"]);
+        }
+        let tls = tokio_native_tls::TlsConnector::from(tls.build()?);
+        let https = HttpsConnector::from((http, tls));
+
+        let mut builder = Client::builder(TokioExecutor::new());
+        builder
+            .pool_timer(TokioTimer::new())
+            .pool_idle_timeout(Duration::from_millis(
+                config
+                    .pool_idle_timeout
+                    .unwrap_or(DEFAULT_POOL_IDLE_TIMEOUT),
+            ));
+        if let Some(max_idle) = config.max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle);
+        }
+
+        Ok(OutboundClient {
+            client: builder.build(https),
+            request_timeout: Duration::from_millis(
+                config.request_timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT),
+            ),
+            metrics,
+        })
+    }
+
+    /// Send a request and read the response. Upstream responses are small, and are
+    /// read in full so that they can be returned to clients after retries.
+    pub async fn send(&self, req: Request<Full<Bytes>>) -> Result<Response<Bytes>, ClientError> {
+        let host = req.uri().host().unwrap_or("").to_string();
+        let start = Instant::now();
+        let response_res = match tokio::time::timeout(self.request_timeout, self.request(req)).await
+        {
+            Ok(response_res) => response_res,
+            Err(_) => Err(ClientError::Timeout),
+        };
+        self.metrics
+            .upstream_latency
+            .with_label_values(&[&host])
+            .observe(start.elapsed().as_secs_f64());
+        let status = response_res.as_ref().ok().map(|response| response.status());
+        self.metrics
+            .outbound_attempts
+            .with_label_values(&[&host, metrics::status_class(status)])
+            .inc();
+
+        response_res
+    }
+
+    async fn request(&self, req: Request<Full<Bytes>>) -> Result<Response<Bytes>, ClientError> {
+        let response = self
+            .client
+            .request(req)
+            .await
+            .map_err(ClientError::Request)?;
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.map_err(ClientError::Body)?.to_bytes();
+
+        Ok(Response::from_parts(parts, body))
+    }
+}
@@ -0,0 +1,114 @@
+use std::io::prelude::*;
+use std::ops::RangeInclusive;
+
+use flate2::write::GzEncoder;
+use hyper::body::Bytes;
+use hyper::header::HeaderValue;
+use serde::{Deserialize, Serialize};
+
+/// Codecs that outbound request bodies can be compressed with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Codec {
+    /// The levels the codec supports, and the level used when none is configured.
+    fn levels(&self) -> (RangeInclusive<i32>, i32) {
+        match self {
+            Codec::None => (0..=0, 0),
+            Codec::Gzip => (0..=9, 6),
+            Codec::Zstd => (1..=22, 3),
+        }
+    }
+}
+
+/// Compression applied to the bodies of requests sent to an outbound DSN.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Compression {
+    codec: Codec,
+    level: i32,
+}
+
+impl Compression {
+    /// Create compression settings. Returns the level on failure when it isn't
+    /// supported by the codec.
+    pub fn new(codec: Option<Codec>, level: Option<i32>) -> Result<Compression, i32> {
+        let codec = codec.unwrap_or_default();
+        let (levels, default_level) = codec.levels();
+        let level = match level {
+            Some(level) if codec == Codec::None || levels.contains(&level) => level,
+            Some(level) => return Err(level),
+            None => default_level,
+        };
+
+        Ok(Compression { codec, level })
+    }
+
+    /// The `Content-Encoding` of compressed bodies. Unset when bodies are not compressed.
+    pub fn content_encoding(&self) -> Option<HeaderValue> {
+        match self.codec {
+            Codec::None => None,
+            Codec::Gzip => Some(HeaderValue::from_static("gzip")),
+            Codec::Zstd => Some(HeaderValue::from_static("zstd")),
+        }
+    }
+
+    /// Compress a body. Bodies are returned unchanged when compression is disabled.
+    pub fn compress(&self, body: &Bytes) -> std::io::Result<Bytes> {
+        let compressed = match self.codec {
+            Codec::None => return Ok(body.clone()),
+            Codec::Gzip => {
+                let level = flate2::Compression::new(self.level as u32);
+                let mut encoder = GzEncoder::new(Vec::with_capacity(body.len() / 4), level);
+                encoder.write_all(body)?;
+                encoder.finish()?
+            }
+            Codec::Zstd => zstd::encode_all(&body[..], self.level)?,
+        };
+
+        Ok(Bytes::from(compressed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::decode_body;
+
+    #[test]
+    fn levels() {
+        assert_eq!(
+            Compression::new(None, None).unwrap(),
+            Compression::default()
+        );
+        let gzip = Compression::new(Some(Codec::Gzip), None).unwrap();
+        assert_eq!(gzip.level, 6);
+        let zstd = Compression::new(Some(Codec::Zstd), Some(19)).unwrap();
+        assert_eq!(zstd.level, 19);
+
+        assert_eq!(Compression::new(Some(Codec::Gzip), Some(10)), Err(10));
+        assert_eq!(Compression::new(Some(Codec::Zstd), Some(0)), Err(0));
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let body = Bytes::from("{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}\n".repeat(20));
+        let none = Compression::default();
+        assert_eq!(none.content_encoding(), None);
+        assert_eq!(none.compress(&body).unwrap(), body);
+
+        for codec in [Codec::Gzip, Codec::Zstd] {
+            let compression = Compression::new(Some(codec), None).unwrap();
+            let compressed = compression.compress(&body).unwrap();
+            assert!(compressed.len() < body.len(), "{codec:?}");
+
+            let encoding = compression.content_encoding().unwrap();
+            assert_eq!(decode_body(&encoding, &compressed).unwrap(), body);
+        }
+    }
+}
@@ -1,39 +1,908 @@
-use serde::{Deserialize, Serialize};
+use serde::de::{self, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::BTreeMap;
 use std::path::Path;
-use std::{fs, io};
+use std::{fmt, fs, io};
+
+use crate::compression::{Codec, Compression};
+use crate::dsn::DsnParseError;
+use crate::envelope::ItemFilter;
+use crate::sampling::SampleRates;
+use crate::schedule::Schedule;
 
 /// A set of inbound and outbound keys.
 /// Requests sent to an inbound DSN are mirrored to all outbound DSNs
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct KeyRing {
     /// Inbound keys are virtual DSNs that the mirror will accept traffic on
     pub inbound: Option<String>,
     /// One or more upstream DSN keys that the mirror will forward traffic to.
-    pub outbound: Vec<Option<String>>,
+    pub outbound: Vec<Option<OutboundKey>>,
+    /// Spool settings for deliveries that fail. When unset failed deliveries are dropped.
+    pub spool: Option<SpoolConfig>,
+    /// Origins that browsers can send requests from, like `https://app.example.com`
+    /// or `*.example.com`. All origins are allowed when unset.
+    pub allowed_origins: Option<Vec<String>>,
+}
+
+/// An outbound DSN. Either a plain DSN string, or a mapping
+/// with the DSN and settings for that destination.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum OutboundKey {
+    Dsn(String),
+    Options(Box<OutboundOptions>),
+}
+
+// Deserialized by hand instead of as an untagged enum, so that errors in the
+// options of an outbound DSN, like unknown fields, are reported as they are.
+impl<'de> Deserialize<'de> for OutboundKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OutboundKeyVisitor;
+
+        impl<'de> Visitor<'de> for OutboundKeyVisitor {
+            type Value = OutboundKey;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a DSN or a mapping with a `dsn`")
+            }
+
+            fn visit_str<E: de::Error>(self, dsn: &str) -> Result<OutboundKey, E> {
+                Ok(OutboundKey::Dsn(dsn.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<OutboundKey, A::Error> {
+                let options =
+                    OutboundOptions::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(OutboundKey::Options(Box::new(options)))
+            }
+        }
+
+        deserializer.deserialize_any(OutboundKeyVisitor)
+    }
+}
+
+impl OutboundKey {
+    /// Get the DSN string for this outbound key.
+    pub fn dsn(&self) -> &str {
+        match self {
+            OutboundKey::Dsn(dsn) => dsn,
+            OutboundKey::Options(options) => &options.dsn,
+        }
+    }
+
+    /// Whether or not this outbound key is the keyring's primary destination.
+    pub fn is_primary(&self) -> bool {
+        match self {
+            OutboundKey::Dsn(_) => false,
+            OutboundKey::Options(options) => options.primary,
+        }
+    }
+
+    /// Whether or not store requests are converted to envelopes for this outbound key.
+    pub fn store_as_envelope(&self) -> bool {
+        match self {
+            OutboundKey::Dsn(_) => false,
+            OutboundKey::Options(options) => options.store_as_envelope,
+        }
+    }
+
+    /// Get the retry settings for this outbound key if it has any.
+    pub fn retry(&self) -> Option<&RetryConfig> {
+        match self {
+            OutboundKey::Dsn(_) => None,
+            OutboundKey::Options(options) => options.retry.as_ref(),
+        }
+    }
+
+    /// Get the envelope item types forwarded to this outbound key.
+    pub fn items(&self) -> ItemFilter {
+        match self {
+            OutboundKey::Dsn(_) => ItemFilter::default(),
+            OutboundKey::Options(options) => {
+                ItemFilter::new(options.include_items.clone(), options.exclude_items.clone())
+            }
+        }
+    }
+
+    /// Get the sample rates for this outbound key.
+    pub fn sample_rates(&self) -> SampleRates {
+        match self {
+            OutboundKey::Dsn(_) => SampleRates::default(),
+            OutboundKey::Options(options) => {
+                SampleRates::new(options.sample_rate, options.item_sample_rates.clone())
+            }
+        }
+    }
+
+    /// Get the compression for bodies sent to this outbound key. Returns the level
+    /// on failure when the codec doesn't support it.
+    pub fn compression(&self) -> Result<Compression, i32> {
+        match self {
+            OutboundKey::Dsn(_) => Ok(Compression::default()),
+            OutboundKey::Options(options) => {
+                Compression::new(options.compression, options.compression_level)
+            }
+        }
+    }
+
+    /// Get the time window this outbound key is active in. Returns the name of
+    /// the field that is not a valid timestamp on failure.
+    pub fn schedule(&self) -> Result<Schedule, &'static str> {
+        match self {
+            OutboundKey::Dsn(_) => Ok(Schedule::default()),
+            OutboundKey::Options(options) => Schedule::new(
+                options.active_from.as_deref(),
+                options.active_until.as_deref(),
+            ),
+        }
+    }
+}
+
+impl From<&str> for OutboundKey {
+    fn from(dsn: &str) -> Self {
+        OutboundKey::Dsn(dsn.to_string())
+    }
+}
+
+/// An outbound DSN with settings for that destination.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OutboundOptions {
+    /// The upstream DSN
+    pub dsn: String,
+    /// The response from the primary DSN is returned to the client, including its
+    /// status code and rate limits. Only one outbound DSN per keyring can be primary.
+    #[serde(default)]
+    pub primary: bool,
+    /// Retry settings for this DSN. Unset fields use the top-level `retry` settings.
+    pub retry: Option<RetryConfig>,
+    /// Envelope item types forwarded to this DSN, like `event` or `replay_recording`.
+    /// All item types are forwarded when unset.
+    pub include_items: Option<Vec<String>>,
+    /// Envelope item types that are not forwarded to this DSN.
+    pub exclude_items: Option<Vec<String>>,
+    /// The fraction of events and traces forwarded to this DSN, from 0.0 to 1.0.
+    /// Defaults to 1.0.
+    pub sample_rate: Option<f64>,
+    /// Sample rates for envelope item types, like `replay_recording: 0.1`.
+    /// Item types that aren't listed use `sample_rate`.
+    pub item_sample_rates: Option<BTreeMap<String, f64>>,
+    /// An RFC 3339 timestamp that requests are sent to this DSN from.
+    pub active_from: Option<String>,
+    /// An RFC 3339 timestamp that requests are sent to this DSN until.
+    pub active_until: Option<String>,
+    /// Convert events sent to the legacy store endpoint into envelopes, and send them
+    /// to the envelope endpoint.
+    #[serde(default)]
+    pub store_as_envelope: bool,
+    /// Compress request bodies sent to this DSN with `gzip` or `zstd`. Defaults to `none`.
+    pub compression: Option<Codec>,
+    /// The compression level, from 0 to 9 for gzip and 1 to 22 for zstd.
+    /// Defaults to 6 for gzip and 3 for zstd.
+    pub compression_level: Option<i32>,
+}
+
+/// Retry settings for failed outbound requests.
+/// Connection errors, timeouts, 5xx and 429 responses are retried.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// The maximum number of attempts, including the first one. Defaults to 1 (no retries).
+    pub max_attempts: Option<u32>,
+    /// The delay in milliseconds before the first retry. Defaults to 100.
+    pub base_backoff: Option<u64>,
+    /// The longest delay in milliseconds between attempts. Defaults to 10000.
+    /// Upstreams that ask for a longer delay are not retried.
+    pub max_backoff: Option<u64>,
+    /// Whether or not to randomize delays. Defaults to true.
+    pub jitter: Option<bool>,
+}
+
+impl RetryConfig {
+    /// Fill unset values in `self` from `fallback`.
+    pub fn or(&self, fallback: &RetryConfig) -> RetryConfig {
+        RetryConfig {
+            max_attempts: self.max_attempts.or(fallback.max_attempts),
+            base_backoff: self.base_backoff.or(fallback.base_backoff),
+            max_backoff: self.max_backoff.or(fallback.max_backoff),
+            jitter: self.jitter.or(fallback.jitter),
+        }
+    }
+}
+
+/// Settings for the HTTP client used for outbound requests.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Timeout in milliseconds for establishing connections. Defaults to 5000.
+    pub connect_timeout: Option<u64>,
+    /// Timeout in milliseconds for receiving a response. Defaults to 30000.
+    pub request_timeout: Option<u64>,
+    /// How long in milliseconds idle connections are kept open. Defaults to 90000.
+    pub pool_idle_timeout: Option<u64>,
+    /// The maximum number of idle connections kept for each upstream host. Defaults to no limit.
+    pub max_idle_per_host: Option<usize>,
+    /// Whether or not to offer HTTP/2 to upstreams that support it. Defaults to false.
+    pub http2: Option<bool>,
+}
+
+/// Settings for inbound connections. Connections can use HTTP/1.1, or HTTP/2 with prior knowledge.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Whether or not to keep HTTP/1.1 connections open between requests. Defaults to true.
+    pub keep_alive: Option<bool>,
+    /// The maximum number of concurrent streams on each HTTP/2 connection. Defaults to 200.
+    pub max_concurrent_streams: Option<u32>,
+    /// Interval in milliseconds between HTTP/2 keep-alive pings. Pings are disabled when unset.
+    pub keep_alive_interval: Option<u64>,
+    /// Timeout in milliseconds for acknowledging HTTP/2 keep-alive pings. Defaults to 20000.
+    pub keep_alive_timeout: Option<u64>,
+}
+
+/// Certificates for terminating TLS on the listener. Certificates are reloaded on SIGHUP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// Path to the PEM encoded certificate chain.
+    pub cert_path: String,
+    /// Path to the PEM encoded private key.
+    pub key_path: String,
+    /// Path to PEM encoded CA certificates used to verify client certificates.
+    /// Client certificates are not requested when unset.
+    pub client_ca_path: Option<String>,
+    /// Whether or not clients without a certificate are accepted when `client_ca_path`
+    /// is set. Defaults to false.
+    pub client_cert_optional: Option<bool>,
+}
+
+/// Settings for the `/readyz` endpoint.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Whether or not to check that outbound hosts accept connections. Defaults to false.
+    pub check_upstreams: Option<bool>,
+    /// Timeout in milliseconds for connecting to each outbound host. Defaults to 2000.
+    pub upstream_timeout: Option<u64>,
+}
+
+/// Limits for the on-disk spool of a keyring.
+/// Each outbound DSN gets its own spool, and the limits apply to each of them.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpoolConfig {
+    /// The maximum number of bytes spooled per outbound DSN. The oldest requests are discarded first.
+    pub max_size: Option<u64>,
+    /// The maximum age in seconds of a spooled request. Older requests are discarded instead of replayed.
+    pub max_age: Option<u64>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigData {
     /// The inbound IP to use. Defaults to 127.0.0.1
     pub ip: Option<String>,
     /// The port the http server will listen on
     pub port: Option<u16>,
+    /// The port the admin server with the `/metrics` endpoint listens on.
+    /// The admin server is disabled when unset.
+    pub admin_port: Option<u16>,
+    /// Directory that failed deliveries are spooled to. Spooling is disabled when unset.
+    pub spool_path: Option<String>,
+    /// How often in seconds the spool is replayed. Defaults to 30 seconds.
+    pub spool_interval: Option<u64>,
+    /// Respond to clients as soon as an outbound DSN responds successfully instead of
+    /// waiting for every outbound request to complete. Defaults to false.
+    pub answer_early: Option<bool>,
+    /// How often in seconds the configuration file is checked for changes. Defaults to 5 seconds.
+    /// Changes to `keys` and `retry` are applied without a restart, other settings require a restart.
+    pub reload_interval: Option<u64>,
+    /// How long in seconds to wait for in-flight requests and deliveries when shutting down.
+    /// Defaults to 30 seconds.
+    pub shutdown_timeout: Option<u64>,
+    /// Settings for inbound connections.
+    pub server: Option<ServerConfig>,
+    /// Terminate TLS on the listener. Connections use plain HTTP when unset.
+    pub tls: Option<TlsConfig>,
+    /// Settings for the outbound HTTP client.
+    pub client: Option<ClientConfig>,
+    /// Default retry settings for all outbound DSNs.
+    pub retry: Option<RetryConfig>,
+    /// Settings for the health check endpoints.
+    pub health: Option<HealthConfig>,
     /// A list of keypairs that the server will handle.
     pub keys: Vec<KeyRing>,
 }
 
-#[derive(Debug, Clone)]
-pub struct ConfigError;
+/// Errors found while loading or validating configuration.
+/// Key and outbound positions are indexes into the `keys` and `outbound` lists.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The configuration file could not be read.
+    Io(io::Error),
+    /// The configuration file is not valid YAML, or has unexpected fields or values.
+    Parse {
+        message: String,
+        line: Option<usize>,
+        column: Option<usize>,
+    },
+    /// A key is missing its inbound DSN.
+    MissingInbound { key: usize },
+    /// An inbound DSN could not be parsed. Only the host of the DSN is kept,
+    /// as DSNs can contain interpolated secrets.
+    InvalidInbound {
+        key: usize,
+        host: Option<String>,
+        error: DsnParseError,
+    },
+    /// An outbound DSN could not be parsed. Only the host of the DSN is kept.
+    InvalidOutbound {
+        key: usize,
+        outbound: usize,
+        host: Option<String>,
+        error: DsnParseError,
+    },
+    /// Two keys have inbound DSNs with the same public key.
+    DuplicateInbound {
+        key: usize,
+        previous: usize,
+        public_key: String,
+    },
+    /// A key has no outbound DSNs.
+    EmptyOutbound { key: usize },
+    /// A key has more than one primary outbound DSN.
+    MultiplePrimary { key: usize },
+    /// An outbound DSN is one of the mirror's own inbound DSNs.
+    OutboundIsMirror { key: usize, outbound: usize },
+    /// An outbound DSN has a sample rate that is not between 0.0 and 1.0.
+    InvalidSampleRate {
+        key: usize,
+        outbound: usize,
+        rate: f64,
+    },
+    /// An outbound DSN has an `active_from` or `active_until` that is not an RFC 3339 timestamp.
+    InvalidTimestamp {
+        key: usize,
+        outbound: usize,
+        field: &'static str,
+    },
+    /// An outbound DSN has an `active_until` that is not after its `active_from`.
+    EmptySchedule { key: usize, outbound: usize },
+    /// An outbound DSN includes or excludes an envelope item type that doesn't exist.
+    UnknownItemType {
+        key: usize,
+        outbound: usize,
+        item_type: String,
+    },
+    /// An outbound DSN has a compression level that its codec doesn't support.
+    InvalidCompressionLevel {
+        key: usize,
+        outbound: usize,
+        level: i32,
+    },
+    /// A field references an environment variable that is not set.
+    MissingVariable { field: String, name: String },
+    /// A field references a secret file that could not be read.
+    SecretFile {
+        field: String,
+        path: String,
+        error: io::Error,
+    },
+    /// A field has a `${` without a closing `}`.
+    UnterminatedVariable { field: String },
+    /// A `SENTRY_MIRROR_*` environment variable has an invalid value.
+    InvalidEnvironment { name: String, value: String },
+    /// An interval in seconds is 0.
+    ZeroInterval { field: &'static str },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "could not read configuration: {e}"),
+            ConfigError::Parse {
+                message,
+                line: Some(line),
+                column: Some(column),
+            } => write!(f, "line {line} column {column}: {message}"),
+            ConfigError::Parse { message, .. } => write!(f, "{message}"),
+            ConfigError::MissingInbound { key } => {
+                write!(f, "keys[{key}]: missing inbound DSN")
+            }
+            ConfigError::InvalidInbound {
+                key,
+                host: Some(host),
+                error,
+            } => write!(f, "keys[{key}].inbound: invalid DSN for `{host}`: {error}"),
+            ConfigError::InvalidInbound { key, error, .. } => {
+                write!(f, "keys[{key}].inbound: invalid DSN: {error}")
+            }
+            ConfigError::InvalidOutbound {
+                key,
+                outbound,
+                host: Some(host),
+                error,
+            } => write!(
+                f,
+                "keys[{key}].outbound[{outbound}]: invalid DSN for `{host}`: {error}"
+            ),
+            ConfigError::InvalidOutbound {
+                key,
+                outbound,
+                error,
+                ..
+            } => write!(f, "keys[{key}].outbound[{outbound}]: invalid DSN: {error}"),
+            ConfigError::DuplicateInbound {
+                key,
+                previous,
+                public_key,
+            } => write!(
+                f,
+                "keys[{key}].inbound: public key `{public_key}` is already used by keys[{previous}]"
+            ),
+            ConfigError::EmptyOutbound { key } => {
+                write!(
+                    f,
+                    "keys[{key}].outbound: at least one outbound DSN is required"
+                )
+            }
+            ConfigError::MultiplePrimary { key } => {
+                write!(
+                    f,
+                    "keys[{key}].outbound: only one outbound DSN can be primary"
+                )
+            }
+            ConfigError::OutboundIsMirror { key, outbound } => write!(
+                f,
+                "keys[{key}].outbound[{outbound}]: outbound DSN points back at the mirror"
+            ),
+            ConfigError::InvalidSampleRate {
+                key,
+                outbound,
+                rate,
+            } => write!(
+                f,
+                "keys[{key}].outbound[{outbound}]: sample rate {rate} is not between 0.0 and 1.0"
+            ),
+            ConfigError::InvalidTimestamp {
+                key,
+                outbound,
+                field,
+            } => write!(
+                f,
+                "keys[{key}].outbound[{outbound}].{field}: expected an RFC 3339 timestamp like `2024-06-01T00:00:00Z`"
+            ),
+            ConfigError::EmptySchedule { key, outbound } => write!(
+                f,
+                "keys[{key}].outbound[{outbound}]: active_until must be after active_from"
+            ),
+            ConfigError::UnknownItemType {
+                key,
+                outbound,
+                item_type,
+            } => write!(
+                f,
+                "keys[{key}].outbound[{outbound}]: unknown envelope item type `{item_type}`"
+            ),
+            ConfigError::InvalidCompressionLevel {
+                key,
+                outbound,
+                level,
+            } => write!(
+                f,
+                "keys[{key}].outbound[{outbound}].compression_level: level {level} is not supported by the codec"
+            ),
+            ConfigError::MissingVariable { field, name } => {
+                write!(f, "{field}: environment variable `{name}` is not set")
+            }
+            ConfigError::SecretFile { field, path, error } => {
+                write!(f, "{field}: could not read secret file `{path}`: {error}")
+            }
+            ConfigError::UnterminatedVariable { field } => {
+                write!(f, "{field}: `${{` is missing a closing `}}`")
+            }
+            ConfigError::InvalidEnvironment { name, value } => {
+                write!(
+                    f,
+                    "environment variable {name} has an invalid value `{value}`"
+                )
+            }
+            ConfigError::ZeroInterval { field } => {
+                write!(f, "{field}: must be at least 1 second")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
 
-/// Load configuration data from a path and parse it into `ConfigData`
+/// Environment variables that override configuration file values.
+pub const IP_ENV: &str = "SENTRY_MIRROR_IP";
+pub const PORT_ENV: &str = "SENTRY_MIRROR_PORT";
+
+/// Load configuration data from a path and parse it into `ConfigData`.
+/// `${NAME}` and `${file:/path}` in string values are replaced with the value of the
+/// environment variable or the contents of the file, and `SENTRY_MIRROR_IP` and
+/// `SENTRY_MIRROR_PORT` override `ip` and `port`.
 pub fn load_config(path: &Path) -> Result<ConfigData, ConfigError> {
-    let f = match fs::File::open(path) {
-        Ok(f) => f,
-        Err(_) => return Err(ConfigError),
-    };
-    let configdata = match serde_yaml::from_reader(io::BufReader::new(f)) {
-        Ok(data) => data,
-        Err(_) => return Err(ConfigError),
+    let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+    let mut document: serde_yaml::Value = serde_yaml::from_str(&contents).map_err(parse_error)?;
+
+    let lookup = |name: &str| std::env::var(name).ok();
+    interpolate_value(&mut document, "", &lookup)?;
+    let mut configdata: ConfigData = match serde_yaml::from_value(document) {
+        Ok(configdata) => configdata,
+        // Values don't keep their position in the file. Interpolation only replaces
+        // strings with strings, so the file has the same error at a known position.
+        Err(e) => {
+            return Err(parse_error(
+                serde_yaml::from_str::<ConfigData>(&contents)
+                    .err()
+                    .unwrap_or(e),
+            ))
+        }
     };
+    apply_env_overrides(&mut configdata, &lookup)?;
+    check_intervals(&configdata)?;
+
     Ok(configdata)
 }
+
+/// Check that intervals are at least a second, as they drive timers that can't tick at 0.
+fn check_intervals(configdata: &ConfigData) -> Result<(), ConfigError> {
+    let intervals = [
+        ("reload_interval", configdata.reload_interval),
+        ("spool_interval", configdata.spool_interval),
+    ];
+    for (field, interval) in intervals {
+        if interval == Some(0) {
+            return Err(ConfigError::ZeroInterval { field });
+        }
+    }
+    Ok(())
+}
+
+fn parse_error(e: serde_yaml::Error) -> ConfigError {
+    let location = e.location();
+    let mut message = e.to_string();
+    // The location is reported separately
+    if let Some(l) = &location {
+        let position = format!(" at line {0} column {1}", l.line(), l.column());
+        message = message.replacen(&position, "", 1);
+    }
+    ConfigError::Parse {
+        message,
+        line: location.as_ref().map(|l| l.line()),
+        column: location.as_ref().map(|l| l.column()),
+    }
+}
+
+/// Replace variables in every string value of a YAML document.
+/// `field` is the path to `value` and is used in error messages.
+fn interpolate_value(
+    value: &mut serde_yaml::Value,
+    field: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    match value {
+        serde_yaml::Value::String(s) => {
+            *s = interpolate(s, field, lookup)?;
+        }
+        serde_yaml::Value::Sequence(items) => {
+            for (i, item) in items.iter_mut().enumerate() {
+                interpolate_value(item, &format!("{field}[{i}]"), lookup)?;
+            }
+        }
+        serde_yaml::Value::Mapping(mapping) => {
+            for (key, item) in mapping.iter_mut() {
+                let name = key.as_str().unwrap_or("?");
+                let child = if field.is_empty() {
+                    name.to_string()
+                } else {
+                    format!("{field}.{name}")
+                };
+                interpolate_value(item, &child, lookup)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Replace `${NAME}` with environment variables and `${file:/path}` with the contents
+/// of files in `input`. Trailing newlines are removed from file contents. `$${` is
+/// an escaped `${`.
+fn interpolate(
+    input: &str,
+    field: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, ConfigError> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            output.push_str(&rest[..start - 1]);
+            output.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        output.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => {
+                return Err(ConfigError::UnterminatedVariable {
+                    field: field.to_string(),
+                })
+            }
+        };
+        let name = &rest[start + 2..end];
+        if let Some(file_path) = name.strip_prefix("file:") {
+            let contents =
+                fs::read_to_string(file_path).map_err(|error| ConfigError::SecretFile {
+                    field: field.to_string(),
+                    path: file_path.to_string(),
+                    error,
+                })?;
+            output.push_str(contents.trim_end_matches(['\r', '\n']));
+        } else {
+            match lookup(name) {
+                Some(value) => output.push_str(&value),
+                None => {
+                    return Err(ConfigError::MissingVariable {
+                        field: field.to_string(),
+                        name: name.to_string(),
+                    })
+                }
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    output.push_str(rest);
+
+    Ok(output)
+}
+
+/// Override `ip` and `port` with `SENTRY_MIRROR_IP` and `SENTRY_MIRROR_PORT`.
+fn apply_env_overrides(
+    configdata: &mut ConfigData,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<(), ConfigError> {
+    if let Some(ip) = lookup(IP_ENV) {
+        configdata.ip = Some(ip);
+    }
+    if let Some(port) = lookup(PORT_ENV) {
+        match port.parse::<u16>() {
+            Ok(port) => configdata.port = Some(port),
+            Err(_) => {
+                return Err(ConfigError::InvalidEnvironment {
+                    name: PORT_ENV.to_string(),
+                    value: port,
+                })
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    #[test]
+    fn parse_outbound_keys() {
+        let yaml = r#"
+port: 3000
+retry:
+  max_attempts: 3
+keys:
+  - inbound: https://abcdef@sentry.io/1234
+    outbound:
+      - https://ghijkl@sentry.io/567
+      - dsn: https://mnopq@sentry.io/890
+        primary: true
+        retry:
+          max_attempts: 5
+          jitter: false
+        sample_rate: 0.1
+        item_sample_rates:
+          replay_recording: 0
+        active_until: 2024-06-01T00:00:00Z
+        store_as_envelope: true
+        compression: zstd
+        compression_level: 10
+"#;
+        let configdata: ConfigData = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(configdata.retry.unwrap().max_attempts, Some(3));
+
+        let outbound = &configdata.keys[0].outbound;
+        assert_eq!(outbound[0], Some("https://ghijkl@sentry.io/567".into()));
+
+        let detailed = outbound[1].as_ref().unwrap();
+        assert_eq!(detailed.dsn(), "https://mnopq@sentry.io/890");
+        assert!(detailed.is_primary());
+        assert!(!outbound[0].as_ref().unwrap().is_primary());
+        assert!(detailed.store_as_envelope());
+        let retry = detailed.retry().unwrap();
+        assert_eq!(retry.max_attempts, Some(5));
+        assert_eq!(retry.jitter, Some(false));
+        let sample_rates = detailed.sample_rates();
+        assert_eq!(sample_rates.rate(Some("event")), 0.1);
+        assert_eq!(sample_rates.rate(Some("replay_recording")), 0.0);
+        let schedule = detailed.schedule().unwrap();
+        assert!(!schedule.is_active(std::time::SystemTime::now()));
+        let compression = detailed.compression().unwrap();
+        assert_eq!(
+            compression,
+            Compression::new(Some(Codec::Zstd), Some(10)).unwrap()
+        );
+        assert_eq!(
+            outbound[0].as_ref().unwrap().compression(),
+            Ok(Compression::default())
+        );
+    }
+
+    /// Load configuration from a YAML string through a temporary file.
+    fn load_yaml(yaml: &str) -> Result<ConfigData, ConfigError> {
+        let dir = TempDir::new("config");
+        load_config(&dir.write("config.yml", yaml))
+    }
+
+    #[test]
+    fn load_config_parse_error_location() {
+        let err = load_yaml("port: 3000\nkeys:\n  - inbound: [\n").unwrap_err();
+
+        match err {
+            ConfigError::Parse { line, .. } => assert!(line.is_some()),
+            _ => panic!("Expected a parse error, got {err:?}"),
+        }
+    }
+
+    #[test]
+    fn load_config_unknown_field() {
+        let yaml = "port: 3000\nkeys:\n  - inbound: http://abcdef@localhost/1\n    outbund: []\n";
+        let err = load_yaml(yaml).unwrap_err();
+
+        match err {
+            ConfigError::Parse { message, line, .. } => {
+                assert!(message.contains("unknown field `outbund`"), "{message}");
+                assert_eq!(line, Some(4));
+            }
+            _ => panic!("Expected a parse error, got {err:?}"),
+        }
+    }
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "PUBLIC_KEY" => Some("abcdef".to_string()),
+            "HOST" => Some("o1.ingest.sentry.io".to_string()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn interpolate_env_variables() {
+        let res = interpolate("https://${PUBLIC_KEY}@${HOST}/123", "dsn", &lookup).unwrap();
+        assert_eq!(res, "https://abcdef@o1.ingest.sentry.io/123");
+
+        let res = interpolate("no variables", "dsn", &lookup).unwrap();
+        assert_eq!(res, "no variables");
+
+        let res = interpolate("escaped $${PUBLIC_KEY}", "dsn", &lookup).unwrap();
+        assert_eq!(res, "escaped ${PUBLIC_KEY}");
+    }
+
+    #[test]
+    fn interpolate_missing_variable() {
+        let err =
+            interpolate("https://${MISSING}@sentry.io/1", "keys[0].inbound", &lookup).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "keys[0].inbound: environment variable `MISSING` is not set"
+        );
+
+        let err = interpolate("https://${PUBLIC_KEY", "keys[0].inbound", &lookup).unwrap_err();
+        assert!(matches!(err, ConfigError::UnterminatedVariable { .. }));
+    }
+
+    #[test]
+    fn load_config_unknown_outbound_option() {
+        let yaml = "port: 3000\nkeys:\n  - inbound: http://abcdef@localhost/1\n    outbound:\n      - dsn: https://ghijkl@sentry.io/2\n        primray: true\n";
+        let err = load_yaml(yaml).unwrap_err();
+        match err {
+            ConfigError::Parse { message, line, .. } => {
+                assert!(message.contains("unknown field `primray`"), "{message}");
+                assert_eq!(line, Some(6));
+            }
+            _ => panic!("Expected a parse error, got {err:?}"),
+        }
+
+        let err = load_yaml(
+            "port: 3000\nkeys:\n  - inbound: http://abcdef@localhost/1\n    outbound: [3]\n",
+        )
+        .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("expected a DSN or a mapping with a `dsn`"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn load_config_zero_interval() {
+        let yaml = "port: 3000\nreload_interval: 0\nkeys: []\n";
+        let err = load_yaml(yaml).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "reload_interval: must be at least 1 second"
+        );
+        assert!(load_yaml("port: 3000\nreload_interval: 1\nkeys: []\n").is_ok());
+        let err = load_yaml("port: 3000\nspool_interval: 0\nkeys: []\n").unwrap_err();
+        assert_eq!(err.to_string(), "spool_interval: must be at least 1 second");
+    }
+
+    #[test]
+    fn interpolate_secret_file() {
+        let dir = TempDir::new("secret");
+        let path = dir.write("dsn", "https://secret@sentry.io/1\n");
+        let input = format!("${{file:{0}}}", path.display());
+        let res = interpolate(&input, "dsn", &lookup).unwrap();
+        assert_eq!(res, "https://secret@sentry.io/1");
+
+        let err = interpolate("${file:/does/not/exist}", "dsn", &lookup).unwrap_err();
+        assert!(matches!(err, ConfigError::SecretFile { .. }));
+    }
+
+    #[test]
+    fn interpolate_document_fields() {
+        let yaml = r#"
+port: 3000
+keys:
+  - inbound: https://${PUBLIC_KEY}@mirror.acme.org/1
+    outbound:
+      - https://key@${HOST}/2
+      - dsn: https://${MISSING}@sentry.io/3
+"#;
+        let mut document: serde_yaml::Value = serde_yaml::from_str(yaml).unwrap();
+        let err = interpolate_value(&mut document, "", &lookup).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "keys[0].outbound[1].dsn: environment variable `MISSING` is not set"
+        );
+
+        let yaml = yaml.replace("${MISSING}", "other");
+        let mut document: serde_yaml::Value = serde_yaml::from_str(&yaml).unwrap();
+        interpolate_value(&mut document, "", &lookup).unwrap();
+        let configdata: ConfigData = serde_yaml::from_value(document).unwrap();
+        assert_eq!(
+            configdata.keys[0].inbound.as_deref(),
+            Some("https://abcdef@mirror.acme.org/1")
+        );
+        assert_eq!(
+            configdata.keys[0].outbound[0],
+            Some("https://key@o1.ingest.sentry.io/2".into())
+        );
+    }
+
+    #[test]
+    fn env_overrides() {
+        let mut configdata: ConfigData = serde_yaml::from_str("port: 3000\nkeys: []").unwrap();
+        let overrides = |name: &str| match name {
+            IP_ENV => Some("0.0.0.0".to_string()),
+            PORT_ENV => Some("4000".to_string()),
+            _ => None,
+        };
+        apply_env_overrides(&mut configdata, &overrides).unwrap();
+        assert_eq!(configdata.ip.as_deref(), Some("0.0.0.0"));
+        assert_eq!(configdata.port, Some(4000));
+
+        let invalid = |name: &str| match name {
+            PORT_ENV => Some("not-a-port".to_string()),
+            _ => None,
+        };
+        let err = apply_env_overrides(&mut configdata, &invalid).unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidEnvironment { .. }));
+    }
+}
@@ -0,0 +1,182 @@
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS, ACCESS_CONTROL_MAX_AGE, VARY,
+};
+
+/// Methods that browsers can use for ingest requests. GET and HEAD are only
+/// accepted for cron check-ins.
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, OPTIONS";
+
+/// Request headers that browser SDKs send, including the tracing headers
+/// that are propagated to the mirror.
+const ALLOWED_HEADERS: &str = "x-sentry-auth, x-requested-with, x-forwarded-for, origin, referer, accept, content-type, authentication, authorization, content-encoding, transfer-encoding, sentry-trace, baggage";
+
+/// Response headers that browser SDKs read to respect rate limits.
+const EXPOSE_HEADERS: &str = "x-sentry-error, x-sentry-rate-limits, retry-after";
+
+/// How long in seconds browsers can cache preflight responses.
+const MAX_AGE: &str = "3600";
+
+/// Check the `Origin` of a request against a keyring's `allowed_origins`. Returns
+/// the value of `Access-Control-Allow-Origin`, or `None` when the origin is not allowed.
+/// Requests without an `Origin` are not from browsers and are always allowed.
+pub fn allow_origin(
+    origin: Option<&HeaderValue>,
+    allowed_origins: Option<&[String]>,
+) -> Option<HeaderValue> {
+    let allowed_origins = match allowed_origins {
+        Some(allowed_origins) => allowed_origins,
+        None => return Some(HeaderValue::from_static("*")),
+    };
+    let origin = match origin {
+        Some(origin) => origin,
+        None => return Some(HeaderValue::from_static("*")),
+    };
+    let origin_str = origin.to_str().ok()?.to_ascii_lowercase();
+    let host = origin_str
+        .split_once("://")
+        .map_or(origin_str.as_str(), |(_, host)| host);
+    // Wildcard entries match any port
+    let hostname = match host.rsplit_once(':') {
+        Some((hostname, port)) if port.bytes().all(|b| b.is_ascii_digit()) => hostname,
+        _ => host,
+    };
+    let allowed = allowed_origins.iter().any(|allowed| {
+        let allowed = allowed.to_ascii_lowercase();
+        match allowed.strip_prefix('*') {
+            Some("") => true,
+            Some(suffix) => suffix.starts_with('.') && hostname.ends_with(suffix),
+            None => allowed == origin_str || allowed == host,
+        }
+    });
+
+    allowed.then(|| origin.clone())
+}
+
+/// Add CORS headers to a response. `allow_origin` comes from [`allow_origin`], and is
+/// `None` for rejected origins so that browsers don't expose the response.
+pub fn add_headers(headers: &mut HeaderMap, allow_origin: Option<HeaderValue>) {
+    match allow_origin {
+        Some(allow_origin) if allow_origin == "*" => {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        }
+        Some(allow_origin) => {
+            headers.append(VARY, HeaderValue::from_static("origin"));
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, allow_origin);
+        }
+        None => {
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+    }
+    headers.insert(
+        ACCESS_CONTROL_EXPOSE_HEADERS,
+        HeaderValue::from_static(EXPOSE_HEADERS),
+    );
+    headers.insert(
+        "cross-origin-resource-policy",
+        HeaderValue::from_static("cross-origin"),
+    );
+}
+
+/// Add the headers of a response to a preflight request.
+pub fn add_preflight_headers(headers: &mut HeaderMap) {
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static(ALLOWED_METHODS),
+    );
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static(ALLOWED_HEADERS),
+    );
+    headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static(MAX_AGE));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(origin: Option<&str>, allowed_origins: Option<&[&str]>) -> Option<String> {
+        let origin = origin.map(|origin| HeaderValue::from_str(origin).unwrap());
+        let allowed_origins: Option<Vec<String>> =
+            allowed_origins.map(|list| list.iter().map(|o| o.to_string()).collect());
+        allow_origin(origin.as_ref(), allowed_origins.as_deref())
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn allow_all_origins() {
+        assert_eq!(check(Some("https://example.com"), None), Some("*".into()));
+        assert_eq!(check(None, None), Some("*".into()));
+        // Server SDKs don't send an origin
+        assert_eq!(
+            check(None, Some(&["https://example.com"])),
+            Some("*".into())
+        );
+    }
+
+    #[test]
+    fn allowed_origins() {
+        let allowed: &[&str] = &["https://app.example.com", "*.acme.org", "localhost:3000"];
+        for origin in [
+            "https://app.example.com",
+            "https://APP.example.com",
+            "https://www.acme.org",
+            "http://deep.sub.acme.org",
+            "http://localhost:3000",
+            "https://app.acme.org:8443",
+        ] {
+            assert_eq!(
+                check(Some(origin), Some(allowed)),
+                Some(origin.to_string()),
+                "{origin}"
+            );
+        }
+        for origin in [
+            "http://app.example.com",
+            "https://example.com",
+            "https://acme.org",
+            "https://notacme.org",
+            "http://localhost:3001",
+            "https://acme.org:8443",
+            "https://acme.org:8443.evil.com",
+            "null",
+        ] {
+            assert_eq!(check(Some(origin), Some(allowed)), None, "{origin}");
+        }
+        assert_eq!(
+            check(Some("https://example.com"), Some(&["*"])),
+            Some("https://example.com".into())
+        );
+        assert_eq!(check(Some("https://example.com"), Some(&[])), None);
+    }
+
+    #[test]
+    fn response_headers() {
+        let mut headers = HeaderMap::new();
+        add_headers(&mut headers, Some(HeaderValue::from_static("*")));
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!headers.contains_key(VARY));
+        assert!(!headers.contains_key(ACCESS_CONTROL_MAX_AGE));
+
+        let mut headers = HeaderMap::new();
+        add_headers(
+            &mut headers,
+            Some(HeaderValue::from_static("https://example.com")),
+        );
+        add_preflight_headers(&mut headers);
+        assert_eq!(headers[ACCESS_CONTROL_ALLOW_ORIGIN], "https://example.com");
+        assert_eq!(headers[VARY], "origin");
+        assert_eq!(headers[ACCESS_CONTROL_MAX_AGE], "3600");
+        let allowed_headers = headers[ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap();
+        for name in ["sentry-trace", "baggage", "x-sentry-auth"] {
+            assert!(allowed_headers.contains(name));
+        }
+
+        // Rejected origins vary on the origin without being allowed
+        let mut headers = HeaderMap::new();
+        add_headers(&mut headers, None);
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(headers[VARY], "origin");
+        assert!(headers.contains_key(ACCESS_CONTROL_EXPOSE_HEADERS));
+    }
+}
@@ -0,0 +1,708 @@
+use std::fmt;
+
+use hyper::body::Bytes;
+use serde_json::{Map, Value};
+
+use crate::dsn;
+
+/// Errors found while parsing an envelope.
+/// Item positions are indexes into the envelope's items.
+#[derive(Debug)]
+pub enum EnvelopeError {
+    /// The envelope header is not a JSON object.
+    InvalidHeader(serde_json::Error),
+    /// An item header is not a JSON object.
+    InvalidItemHeader {
+        item: usize,
+        error: serde_json::Error,
+    },
+    /// An item header doesn't have a `type`.
+    MissingItemType { item: usize },
+    /// An item header has a `length` that isn't a number.
+    InvalidLength { item: usize },
+    /// An item payload is shorter than the `length` in its header.
+    PayloadTooShort { item: usize, length: usize },
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EnvelopeError::InvalidHeader(e) => write!(f, "invalid envelope header: {e}"),
+            EnvelopeError::InvalidItemHeader { item, error } => {
+                write!(f, "invalid header for item {item}: {error}")
+            }
+            EnvelopeError::MissingItemType { item } => write!(f, "item {item} has no type"),
+            EnvelopeError::InvalidLength { item } => write!(f, "item {item} has an invalid length"),
+            EnvelopeError::PayloadTooShort { item, length } => {
+                write!(
+                    f,
+                    "item {item} is shorter than its length of {length} bytes"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for EnvelopeError {}
+
+/// A JSON object header, and the bytes it was parsed from. Unmodified headers are
+/// serialized as they were received.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Header {
+    fields: Map<String, Value>,
+    raw: Option<Bytes>,
+}
+
+impl Header {
+    fn parse(raw: Bytes) -> Result<Header, serde_json::Error> {
+        Ok(Header {
+            fields: serde_json::from_slice(&raw)?,
+            raw: Some(raw),
+        })
+    }
+
+    /// Get the trace id of the trace context, or the event id.
+    fn sample_id(&self) -> Option<&str> {
+        self.fields
+            .get("trace")
+            .and_then(|trace| trace.get("trace_id"))
+            .or_else(|| self.fields.get("event_id"))
+            .and_then(Value::as_str)
+    }
+
+    fn has_public_key(&self) -> bool {
+        self.fields
+            .get("trace")
+            .is_some_and(|trace| trace.get("public_key").is_some())
+    }
+
+    fn fields_mut(&mut self) -> &mut Map<String, Value> {
+        self.raw = None;
+        &mut self.fields
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match &self.raw {
+            Some(raw) => out.extend_from_slice(raw),
+            None => {
+                out.extend_from_slice(Value::Object(self.fields.clone()).to_string().as_bytes())
+            }
+        }
+    }
+}
+
+/// An item in an envelope.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Item {
+    header: Header,
+    item_type: String,
+    payload: Bytes,
+    /// Whether or not the item header was followed by a newline. Only the last
+    /// item of an envelope can be missing it.
+    header_newline: bool,
+    /// Whether or not the payload was followed by a newline.
+    payload_newline: bool,
+}
+
+impl Item {
+    /// The item type, like `event` or `replay_recording`.
+    pub fn item_type(&self) -> &str {
+        &self.item_type
+    }
+
+    /// The item payload, without the newline that follows it.
+    pub fn payload(&self) -> &Bytes {
+        &self.payload
+    }
+}
+
+/// A parsed envelope. Envelopes are serialized byte for byte as they were received,
+/// apart from the headers and payloads that were modified.
+/// See https://develop.sentry.dev/sdk/envelopes/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    /// The envelope as it was received. Unset once the envelope is modified.
+    raw: Option<Bytes>,
+    header: Header,
+    /// Whether or not the envelope header was followed by a newline.
+    header_newline: bool,
+    items: Vec<Item>,
+    /// Blank lines after the last item.
+    trailer: Bytes,
+}
+
+impl Envelope {
+    /// Parse an envelope. Payloads share memory with `body`.
+    pub fn parse(body: &Bytes) -> Result<Envelope, EnvelopeError> {
+        let (header_line, header_newline) = line(body, 0);
+        let header =
+            Header::parse(body.slice(0..header_line)).map_err(EnvelopeError::InvalidHeader)?;
+
+        let mut items = Vec::new();
+        let mut offset = header_line + usize::from(header_newline);
+        while offset < body.len() {
+            if body[offset..].iter().all(u8::is_ascii_whitespace) {
+                break;
+            }
+            let item = items.len();
+            let (header_end, header_newline) = line(body, offset);
+            let header = Header::parse(body.slice(offset..header_end))
+                .map_err(|error| EnvelopeError::InvalidItemHeader { item, error })?;
+            let item_type = match header.fields.get("type").and_then(Value::as_str) {
+                Some(item_type) => item_type.to_string(),
+                None => return Err(EnvelopeError::MissingItemType { item }),
+            };
+
+            let payload_start = header_end + usize::from(header_newline);
+            let payload_end = match header.fields.get("length") {
+                Some(length) => {
+                    let length = length
+                        .as_u64()
+                        .and_then(|length| usize::try_from(length).ok())
+                        .ok_or(EnvelopeError::InvalidLength { item })?;
+                    if body.len() - payload_start < length {
+                        return Err(EnvelopeError::PayloadTooShort { item, length });
+                    }
+                    payload_start + length
+                }
+                None => line(body, payload_start).0,
+            };
+            let payload_newline = body.get(payload_end) == Some(&b'\n');
+
+            items.push(Item {
+                header,
+                item_type,
+                payload: body.slice(payload_start..payload_end),
+                header_newline,
+                payload_newline,
+            });
+            offset = payload_end + usize::from(payload_newline);
+        }
+
+        Ok(Envelope {
+            raw: Some(body.clone()),
+            header,
+            header_newline,
+            items,
+            trailer: body.slice(offset.min(body.len())..),
+        })
+    }
+
+    /// Convert an event sent to the legacy store endpoint into an envelope with a
+    /// single `event` or `transaction` item. Returns `None` when `body` is not a JSON object.
+    pub fn from_store(body: &Bytes) -> Option<Envelope> {
+        let event: Map<String, Value> = serde_json::from_slice(body).ok()?;
+        let item_type = match event.get("type").and_then(Value::as_str) {
+            Some("transaction") => "transaction",
+            _ => "event",
+        };
+        let mut header = Map::new();
+        if let Some(event_id) = event.get("event_id") {
+            header.insert("event_id".to_string(), event_id.clone());
+        }
+        let mut item_header = Map::new();
+        item_header.insert("type".to_string(), Value::from(item_type));
+        item_header.insert("length".to_string(), Value::from(body.len()));
+
+        Some(Envelope {
+            raw: None,
+            header: Header {
+                fields: header,
+                raw: None,
+            },
+            header_newline: true,
+            items: vec![Item {
+                header: Header {
+                    fields: item_header,
+                    raw: None,
+                },
+                item_type: item_type.to_string(),
+                payload: body.clone(),
+                header_newline: true,
+                payload_newline: true,
+            }],
+            trailer: Bytes::new(),
+        })
+    }
+
+    pub fn items(&self) -> &[Item] {
+        &self.items
+    }
+
+    /// Get the id that the envelope is sampled by. This is the trace id when the
+    /// envelope has a trace context, so that all envelopes in a trace are sampled together.
+    pub fn sample_id(&self) -> Option<&str> {
+        self.header.sample_id()
+    }
+
+    /// Keep only the items that `keep` returns true for.
+    pub fn retain_items<F>(&mut self, keep: F)
+    where
+        F: FnMut(&Item) -> bool,
+    {
+        let item_count = self.items.len();
+        self.items.retain(keep);
+        if self.items.len() != item_count {
+            self.raw = None;
+        }
+    }
+
+    /// Replace the DSN and the public key of the trace context in the envelope header.
+    /// Headers without them are unchanged.
+    pub fn replace_dsn(&mut self, outbound: &dsn::Dsn) {
+        if self.header.fields.contains_key("dsn") {
+            self.header
+                .fields_mut()
+                .insert("dsn".to_string(), Value::String(outbound.to_string()));
+        }
+        if self.header.has_public_key() {
+            self.header.fields_mut()["trace"]["public_key"] =
+                Value::String(outbound.public_key.clone());
+        }
+        if self.header.raw.is_none() {
+            self.raw = None;
+        }
+    }
+
+    /// Serialize the envelope. Unmodified envelopes share memory with the body they
+    /// were parsed from.
+    pub fn to_bytes(&self) -> Bytes {
+        if let Some(raw) = &self.raw {
+            return raw.clone();
+        }
+        let capacity = self
+            .items
+            .iter()
+            .map(|item| item.payload.len())
+            .sum::<usize>()
+            + self.trailer.len()
+            + 256;
+        let mut out = Vec::with_capacity(capacity);
+        self.header.write(&mut out);
+        if self.header_newline || !self.items.is_empty() {
+            out.push(b'\n');
+        }
+        for item in self.items.iter() {
+            item.header.write(&mut out);
+            if item.header_newline {
+                out.push(b'\n');
+            }
+            out.extend_from_slice(&item.payload);
+            if item.payload_newline {
+                out.push(b'\n');
+            }
+        }
+        out.extend_from_slice(&self.trailer);
+
+        Bytes::from(out)
+    }
+}
+
+/// The header of an envelope, read without the rest of the envelope.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EnvelopeHeader(Header);
+
+impl EnvelopeHeader {
+    /// Parse the first line of an envelope.
+    pub fn parse(line: Bytes) -> Result<EnvelopeHeader, EnvelopeError> {
+        Header::parse(line)
+            .map(EnvelopeHeader)
+            .map_err(EnvelopeError::InvalidHeader)
+    }
+
+    /// See [`Envelope::sample_id`].
+    pub fn sample_id(&self) -> Option<&str> {
+        self.0.sample_id()
+    }
+
+    /// Whether or not [`Envelope::replace_dsn`] would change the header.
+    pub fn has_dsn(&self) -> bool {
+        self.0.fields.contains_key("dsn") || self.0.has_public_key()
+    }
+}
+
+/// Find the end of the line starting at `start`. Returns the position of the
+/// newline, or the end of `body`, and whether or not a newline was found.
+fn line(body: &[u8], start: usize) -> (usize, bool) {
+    match body[start..].iter().position(|&b| b == b'\n') {
+        Some(pos) => (start + pos, true),
+        None => (body.len(), false),
+    }
+}
+
+/// Envelope item types that Sentry accepts.
+pub const ITEM_TYPES: [&str; 25] = [
+    "attachment",
+    "check_in",
+    "client_report",
+    "event",
+    "feedback",
+    "form_data",
+    "log",
+    "metric_buckets",
+    "nel",
+    "otel_log",
+    "otel_span",
+    "profile",
+    "profile_chunk",
+    "raw_security",
+    "replay_event",
+    "replay_recording",
+    "replay_video",
+    "security",
+    "session",
+    "sessions",
+    "span",
+    "statsd",
+    "transaction",
+    "unreal_report",
+    "user_report",
+];
+
+/// Item types that are forwarded to an outbound DSN.
+/// Items are forwarded when they are included, and not excluded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ItemFilter {
+    /// Item types to forward. All item types are included when unset.
+    include: Option<Vec<String>>,
+    /// Item types that are never forwarded.
+    exclude: Vec<String>,
+}
+
+impl ItemFilter {
+    pub fn new(include: Option<Vec<String>>, exclude: Option<Vec<String>>) -> ItemFilter {
+        ItemFilter {
+            include,
+            exclude: exclude.unwrap_or_default(),
+        }
+    }
+
+    /// Whether or not every item type is forwarded.
+    pub fn is_all(&self) -> bool {
+        self.include.is_none() && self.exclude.is_empty()
+    }
+
+    /// Whether or not items of `item_type` are forwarded.
+    pub fn allows(&self, item_type: &str) -> bool {
+        let included = match &self.include {
+            Some(include) => include.iter().any(|t| t == item_type),
+            None => true,
+        };
+        included && !self.exclude.iter().any(|t| t == item_type)
+    }
+
+    /// Find an included or excluded item type that is not one of [`ITEM_TYPES`].
+    pub fn unknown_type(&self) -> Option<&str> {
+        self.include
+            .iter()
+            .flatten()
+            .chain(self.exclude.iter())
+            .map(String::as_str)
+            .find(|item_type| !ITEM_TYPES.contains(item_type))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENVELOPE: &[u8] = b"{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}
+{\"type\":\"event\",\"length\":13}
+{\"message\":1}
+{\"type\":\"attachment\",\"length\":10,\"filename\":\"hello.txt\"}
+\xef\xbb\xbfHello\r\n
+{\"type\":\"replay_recording\"}
+{\"segment_id\":1}
+";
+
+    /// An error with an attachment, as sent by sentry-python.
+    const PYTHON_EVENT: &[u8] = b"{\"event_id\":\"d5a3c56b3e4a4ab59b6ab6ffb5c2d6b1\",\"sent_at\":\"2024-05-14T18:03:27.118571Z\",\"trace\":{\"trace_id\":\"4c79f60c11214eb38604f4ae0781bfb2\",\"environment\":\"production\",\"release\":\"backend@1.0.0\",\"public_key\":\"abcdef\",\"sample_rate\":\"1.0\"}}
+{\"type\":\"event\",\"content_type\":\"application/json\",\"length\":109}
+{\"event_id\":\"d5a3c56b3e4a4ab59b6ab6ffb5c2d6b1\",\"level\":\"error\",\"platform\":\"python\",\"exception\":{\"values\":[]}}
+{\"type\":\"attachment\",\"length\":12,\"filename\":\"log.txt\",\"content_type\":\"text/plain\",\"attachment_type\":\"event.attachment\"}
+line1
+line2
+";
+
+    /// A session update and client report without lengths, as sent by sentry-javascript.
+    const JAVASCRIPT_SESSION: &[u8] = b"{\"sent_at\":\"2024-05-14T18:03:27.118Z\",\"sdk\":{\"name\":\"sentry.javascript.browser\",\"version\":\"7.114.0\"}}
+{\"type\":\"session\"}
+{\"sid\":\"8b4f1b2c0e6b4f5f9c1a7d4e2f3a5b6c\",\"init\":true,\"started\":\"2024-05-14T18:03:27.117Z\",\"status\":\"ok\",\"errors\":0}
+{\"type\":\"client_report\"}
+{\"timestamp\":1715709807.118,\"discarded_events\":[{\"reason\":\"sample_rate\",\"category\":\"transaction\",\"quantity\":1}]}";
+
+    /// A replay with a binary, length-prefixed recording that contains newlines.
+    const JAVASCRIPT_REPLAY: &[u8] = b"{\"event_id\":\"a1b2c3d4e5f60718293a4b5c6d7e8f90\",\"sent_at\":\"2024-05-14T18:03:27.118Z\"}
+{\"type\":\"replay_event\"}
+{\"type\":\"replay_event\",\"replay_id\":\"a1b2c3d4e5f60718293a4b5c6d7e8f90\",\"segment_id\":0}
+{\"type\":\"replay_recording\",\"length\":31}
+{\"segment_id\":0}
+x\x9c\n\x00\n\xff\xfe\r\n\x01\x02\x03\x04\x05";
+
+    /// A check-in followed by trailing blank lines.
+    const CHECK_IN: &[u8] = b"{}
+{\"type\":\"check_in\",\"length\":64}
+{\"check_in_id\":\"83a7c03ed0a04e1b97e2e3b18d38f244\",\"status\":\"ok\"}
+
+\n\n";
+
+    fn filter(include: Option<&[&str]>, exclude: Option<&[&str]>) -> ItemFilter {
+        let to_vec = |types: &[&str]| types.iter().map(|t| t.to_string()).collect();
+        ItemFilter::new(include.map(to_vec), exclude.map(to_vec))
+    }
+
+    fn header_field<'a>(item: &'a Item, name: &str) -> Option<&'a Value> {
+        item.header.fields.get(name)
+    }
+
+    #[test]
+    fn parse_items() {
+        let envelope = Envelope::parse(&Bytes::from_static(ENVELOPE)).unwrap();
+        assert_eq!(
+            envelope.header.fields.get("event_id"),
+            Some(&Value::from("9ec79c33ec9942ab8353589fcb2e04dc"))
+        );
+        let types: Vec<_> = envelope.items().iter().map(Item::item_type).collect();
+        assert_eq!(types, vec!["event", "attachment", "replay_recording"]);
+
+        let attachment = &envelope.items()[1];
+        assert_eq!(header_field(attachment, "length"), Some(&Value::from(10)));
+        assert_eq!(
+            header_field(attachment, "filename"),
+            Some(&Value::from("hello.txt"))
+        );
+        assert_eq!(attachment.payload(), &b"\xef\xbb\xbfHello\r\n"[..]);
+
+        let recording = &envelope.items()[2];
+        assert_eq!(header_field(recording, "length"), None);
+        assert_eq!(recording.payload(), &b"{\"segment_id\":1}"[..]);
+    }
+
+    #[test]
+    fn parse_sdk_envelopes() {
+        let envelope = Envelope::parse(&Bytes::from_static(PYTHON_EVENT)).unwrap();
+        assert_eq!(envelope.items().len(), 2);
+        assert_eq!(
+            header_field(&envelope.items()[0], "content_type"),
+            Some(&Value::from("application/json"))
+        );
+        assert_eq!(envelope.items()[1].payload(), &b"line1\nline2\n"[..]);
+        assert_eq!(
+            header_field(&envelope.items()[1], "attachment_type"),
+            Some(&Value::from("event.attachment"))
+        );
+
+        let envelope = Envelope::parse(&Bytes::from_static(JAVASCRIPT_SESSION)).unwrap();
+        let types: Vec<_> = envelope.items().iter().map(Item::item_type).collect();
+        assert_eq!(types, vec!["session", "client_report"]);
+
+        let envelope = Envelope::parse(&Bytes::from_static(JAVASCRIPT_REPLAY)).unwrap();
+        let recording = &envelope.items()[1];
+        assert_eq!(recording.item_type(), "replay_recording");
+        assert_eq!(recording.payload().len(), 31);
+        assert!(recording.payload().ends_with(b"\x05"));
+
+        let envelope = Envelope::parse(&Bytes::from_static(CHECK_IN)).unwrap();
+        assert_eq!(envelope.items().len(), 1);
+        assert_eq!(envelope.items()[0].item_type(), "check_in");
+    }
+
+    #[test]
+    fn round_trip() {
+        for body in [
+            ENVELOPE,
+            PYTHON_EVENT,
+            JAVASCRIPT_SESSION,
+            JAVASCRIPT_REPLAY,
+            CHECK_IN,
+            b"{}",
+            b"{}\n",
+            b"{}\n{\"type\":\"event\",\"length\":0}",
+            b"{}\n{\"type\":\"event\",\"length\":0}\n",
+            b"{}\n{\"type\":\"event\"}\n",
+            b"{ \"dsn\" : \"https://abcdef@sentry.io/1\" }\n{\"type\":\"event\"}\n{}",
+        ] {
+            let bytes = Bytes::from_static(body);
+            let mut envelope = Envelope::parse(&bytes).unwrap();
+            assert_eq!(envelope.to_bytes(), bytes);
+
+            // Serialize the parsed parts instead of the original body
+            envelope.raw = None;
+            assert_eq!(
+                envelope.to_bytes(),
+                bytes,
+                "{}",
+                String::from_utf8_lossy(body)
+            );
+        }
+    }
+
+    #[test]
+    fn replace_dsn() {
+        let outbound: dsn::Dsn = "https://ghijkl@o1.ingest.sentry.io/2".parse().unwrap();
+        let body = Bytes::from_static(
+            b"{\"dsn\":\"https://abcdef@sentry.io/1\",\"trace\":{\"public_key\":\"abcdef\"}}\n{\"type\":\"event\"}\n{}",
+        );
+        let mut envelope = Envelope::parse(&body).unwrap();
+        envelope.replace_dsn(&outbound);
+        assert_eq!(
+            envelope.to_bytes(),
+            Bytes::from_static(
+                b"{\"dsn\":\"https://ghijkl@o1.ingest.sentry.io/2\",\"trace\":{\"public_key\":\"ghijkl\"}}\n{\"type\":\"event\"}\n{}"
+            )
+        );
+
+        // Headers without a DSN are unchanged
+        let body = Bytes::from_static(PYTHON_EVENT);
+        let mut envelope = Envelope::parse(&body).unwrap();
+        envelope.replace_dsn(&outbound);
+        assert!(envelope.to_bytes().starts_with(b"{\"event_id\""));
+        assert!(envelope.header.fields["trace"]["public_key"] == "ghijkl");
+
+        let body = Bytes::from_static(CHECK_IN);
+        let mut envelope = Envelope::parse(&body).unwrap();
+        envelope.replace_dsn(&outbound);
+        assert_eq!(envelope.to_bytes(), body);
+    }
+
+    #[test]
+    fn sample_ids() {
+        // The trace id takes precedence over the event id
+        let envelope = Envelope::parse(&Bytes::from_static(PYTHON_EVENT)).unwrap();
+        assert_eq!(
+            envelope.sample_id(),
+            Some("4c79f60c11214eb38604f4ae0781bfb2")
+        );
+        let envelope = Envelope::parse(&Bytes::from_static(ENVELOPE)).unwrap();
+        assert_eq!(
+            envelope.sample_id(),
+            Some("9ec79c33ec9942ab8353589fcb2e04dc")
+        );
+        let envelope = Envelope::parse(&Bytes::from_static(CHECK_IN)).unwrap();
+        assert_eq!(envelope.sample_id(), None);
+    }
+
+    #[test]
+    fn parse_headers() {
+        let first_line = |body: &'static [u8]| {
+            let body = Bytes::from_static(body);
+            body.slice(0..line(&body, 0).0)
+        };
+        let header = EnvelopeHeader::parse(first_line(PYTHON_EVENT)).unwrap();
+        assert!(header.has_dsn());
+        assert_eq!(header.sample_id(), Some("4c79f60c11214eb38604f4ae0781bfb2"));
+
+        let header = EnvelopeHeader::parse(first_line(ENVELOPE)).unwrap();
+        assert!(!header.has_dsn());
+        assert_eq!(header.sample_id(), Some("9ec79c33ec9942ab8353589fcb2e04dc"));
+
+        let header = EnvelopeHeader::parse(Bytes::from_static(b"{\"dsn\":null}")).unwrap();
+        assert!(header.has_dsn());
+        assert!(EnvelopeHeader::parse(Bytes::from_static(b"MDMP")).is_err());
+    }
+
+    #[test]
+    fn convert_store_events() {
+        let body = Bytes::from_static(
+            b"{\"event_id\":\"d5a3c56b3e4a4ab59b6ab6ffb5c2d6b1\",\"message\":\"hello\"}",
+        );
+        let envelope = Envelope::from_store(&body).unwrap();
+        assert_eq!(
+            envelope.to_bytes(),
+            Bytes::from_static(
+                b"{\"event_id\":\"d5a3c56b3e4a4ab59b6ab6ffb5c2d6b1\"}
+{\"length\":65,\"type\":\"event\"}
+{\"event_id\":\"d5a3c56b3e4a4ab59b6ab6ffb5c2d6b1\",\"message\":\"hello\"}
+"
+            )
+        );
+        assert_eq!(
+            envelope.sample_id(),
+            Some("d5a3c56b3e4a4ab59b6ab6ffb5c2d6b1")
+        );
+        // The converted envelope can be parsed again
+        let parsed = Envelope::parse(&envelope.to_bytes()).unwrap();
+        assert_eq!(parsed.items()[0].payload(), &body);
+
+        let body = Bytes::from_static(b"{\"type\":\"transaction\",\"spans\":[]}");
+        let envelope = Envelope::from_store(&body).unwrap();
+        assert_eq!(envelope.items()[0].item_type(), "transaction");
+        assert_eq!(envelope.sample_id(), None);
+
+        // Old SDKs could send base64 encoded bodies
+        assert!(
+            Envelope::from_store(&Bytes::from_static(b"eJyrVkrOz0nNVbJSUAIAK9AEdQ==")).is_none()
+        );
+        assert!(Envelope::from_store(&Bytes::from_static(b"[1, 2]")).is_none());
+    }
+
+    #[test]
+    fn parse_errors() {
+        let parse = |body: &'static [u8]| Envelope::parse(&Bytes::from_static(body)).unwrap_err();
+
+        assert!(matches!(parse(b""), EnvelopeError::InvalidHeader(_)));
+        assert!(matches!(
+            parse(b"{}\nnot json\n{}"),
+            EnvelopeError::InvalidItemHeader { item: 0, .. }
+        ));
+        assert!(matches!(
+            parse(b"{}\n{\"type\":\"event\"}\n{}\n{\"length\":2}\n{}"),
+            EnvelopeError::MissingItemType { item: 1 }
+        ));
+        assert!(matches!(
+            parse(b"{}\n{\"type\":\"event\",\"length\":\"2\"}\n{}"),
+            EnvelopeError::InvalidLength { item: 0 }
+        ));
+        assert!(matches!(
+            parse(b"{}\n{\"type\":\"event\",\"length\":100}\n{}"),
+            EnvelopeError::PayloadTooShort {
+                item: 0,
+                length: 100
+            }
+        ));
+    }
+
+    #[test]
+    fn retain_included_items() {
+        let body = Bytes::from_static(ENVELOPE);
+        let mut envelope = Envelope::parse(&body).unwrap();
+        let items = filter(Some(&["event", "transaction"]), None);
+        envelope.retain_items(|item| items.allows(item.item_type()));
+        assert_eq!(
+            envelope.to_bytes(),
+            Bytes::from_static(
+                b"{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}
+{\"type\":\"event\",\"length\":13}
+{\"message\":1}
+"
+            )
+        );
+    }
+
+    #[test]
+    fn retain_items_without_excluded() {
+        let body = Bytes::from_static(ENVELOPE);
+        let mut envelope = Envelope::parse(&body).unwrap();
+        let items = filter(None, Some(&["replay_recording", "event"]));
+        envelope.retain_items(|item| items.allows(item.item_type()));
+        assert_eq!(
+            envelope.to_bytes(),
+            Bytes::from_static(
+                b"{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}
+{\"type\":\"attachment\",\"length\":10,\"filename\":\"hello.txt\"}
+\xef\xbb\xbfHello\r\n
+"
+            )
+        );
+    }
+
+    #[test]
+    fn item_filters() {
+        assert!(filter(None, None).is_all());
+        assert!(filter(None, Some(&["profile"])).allows("event"));
+        assert!(!filter(None, Some(&["profile"])).allows("profile"));
+        assert!(filter(Some(&["event"]), None).allows("event"));
+        assert!(!filter(Some(&["event"]), None).allows("profile"));
+        assert!(!filter(Some(&["event"]), Some(&["event"])).allows("event"));
+    }
+}
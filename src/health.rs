@@ -0,0 +1,280 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use hyper::StatusCode;
+use serde::Serialize;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::dsn;
+
+/// Liveness endpoint, answered whenever the process is serving requests.
+pub const HEALTH_PATH: &str = "/healthz";
+/// Readiness endpoint, reports the loaded keys and optionally upstream reachability.
+pub const READY_PATH: &str = "/readyz";
+
+/// How long the reachability of outbound hosts is reused for readiness checks.
+const UPSTREAM_CHECK_TTL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Liveness {
+    pub status: &'static str,
+}
+
+/// The reachability of an outbound host.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UpstreamStatus {
+    pub host: String,
+    pub port: u16,
+    pub reachable: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Readiness {
+    /// `ready`, `degraded` when some outbound hosts are unreachable, or
+    /// `unavailable` when no keys are configured.
+    pub status: &'static str,
+    /// The number of configured inbound keys.
+    pub keys: usize,
+    /// Outbound hosts that were checked. Empty when upstream checks are disabled.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub upstreams: Vec<UpstreamStatus>,
+}
+
+impl Readiness {
+    /// Degraded upstreams are reported but don't fail the check, as every
+    /// instance of the mirror shares the same upstreams.
+    pub fn status_code(&self) -> StatusCode {
+        if self.keys == 0 {
+            StatusCode::SERVICE_UNAVAILABLE
+        } else {
+            StatusCode::OK
+        }
+    }
+}
+
+/// Checks that outbound hosts accept connections. Results are reused for
+/// `UPSTREAM_CHECK_TTL`, so that frequent probes don't connect to every host each time.
+#[derive(Debug)]
+pub struct UpstreamChecker {
+    timeout: Duration,
+    last_check: Mutex<Option<UpstreamCheck>>,
+}
+
+#[derive(Debug)]
+struct UpstreamCheck {
+    checked_at: Instant,
+    hosts: BTreeSet<(String, u16)>,
+    upstreams: Vec<UpstreamStatus>,
+}
+
+impl UpstreamChecker {
+    /// Create a checker where each host must accept a connection within `timeout`.
+    pub fn new(timeout: Duration) -> UpstreamChecker {
+        UpstreamChecker {
+            timeout,
+            last_check: Mutex::new(None),
+        }
+    }
+
+    /// Check the outbound hosts of a keymap, or reuse the last check of the same hosts.
+    /// Concurrent probes wait for the same check.
+    pub async fn check(&self, keymap: &HashMap<String, dsn::DsnKeyRing>) -> Vec<UpstreamStatus> {
+        let hosts = upstream_hosts(keymap);
+        let mut last_check = self.last_check.lock().await;
+        if let Some(check) = last_check.as_ref() {
+            if check.hosts == hosts && check.checked_at.elapsed() < UPSTREAM_CHECK_TTL {
+                return check.upstreams.clone();
+            }
+        }
+        let upstreams = join_all(
+            hosts
+                .iter()
+                .map(|(host, port)| check_upstream(host.clone(), *port, self.timeout)),
+        )
+        .await;
+        *last_check = Some(UpstreamCheck {
+            checked_at: Instant::now(),
+            hosts,
+            upstreams: upstreams.clone(),
+        });
+
+        upstreams
+    }
+}
+
+pub fn liveness() -> Liveness {
+    Liveness { status: "ok" }
+}
+
+/// Check readiness with the current keymap. When `upstreams` is set, each
+/// outbound host must accept connections.
+pub async fn readiness(
+    keymap: &HashMap<String, dsn::DsnKeyRing>,
+    upstreams: Option<&UpstreamChecker>,
+) -> Readiness {
+    let upstreams = match upstreams {
+        Some(checker) => checker.check(keymap).await,
+        None => Vec::new(),
+    };
+    let status = if keymap.is_empty() {
+        "unavailable"
+    } else if upstreams.iter().any(|upstream| !upstream.reachable) {
+        "degraded"
+    } else {
+        "ready"
+    };
+
+    Readiness {
+        status,
+        keys: keymap.len(),
+        upstreams,
+    }
+}
+
+/// Get the unique outbound hosts and ports in a keymap.
+fn upstream_hosts(keymap: &HashMap<String, dsn::DsnKeyRing>) -> BTreeSet<(String, u16)> {
+    keymap
+        .values()
+        .flat_map(|keyring| keyring.outbound.iter())
+        .map(|outbound| (outbound.dsn.host.clone(), outbound.dsn.port))
+        .collect()
+}
+
+async fn check_upstream(host: String, port: u16, timeout: Duration) -> UpstreamStatus {
+    let error = match tokio::time::timeout(timeout, TcpStream::connect((host.as_str(), port))).await
+    {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some("connection timed out".to_string()),
+    };
+
+    UpstreamStatus {
+        host,
+        port,
+        reachable: error.is_none(),
+        error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::KeyRing;
+
+    fn make_keymap() -> HashMap<String, dsn::DsnKeyRing> {
+        let keys = vec![
+            KeyRing {
+                inbound: Some("https://abcdef@sentry.io/1234".to_string()),
+                outbound: vec![
+                    Some("https://ghijkl@o1.ingest.sentry.io/567".into()),
+                    Some("http://mnopq@self-hosted.example.com/890".into()),
+                    Some("http://stuvw@self-hosted.example.com:9000/891".into()),
+                ],
+                spool: None,
+                allowed_origins: None,
+            },
+            KeyRing {
+                inbound: Some("https://rstuv@sentry.io/1234".to_string()),
+                outbound: vec![Some("https://wxyz@o1.ingest.sentry.io/123".into())],
+                spool: None,
+                allowed_origins: None,
+            },
+        ];
+        dsn::make_key_map(keys, None).unwrap()
+    }
+
+    #[test]
+    fn unique_upstream_hosts() {
+        let hosts: Vec<_> = upstream_hosts(&make_keymap()).into_iter().collect();
+        assert_eq!(
+            hosts,
+            vec![
+                ("o1.ingest.sentry.io".to_string(), 443),
+                ("self-hosted.example.com".to_string(), 80),
+                ("self-hosted.example.com".to_string(), 9000),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn readiness_without_upstream_checks() {
+        let ready = readiness(&make_keymap(), None).await;
+        assert_eq!(ready.status, "ready");
+        assert_eq!(ready.keys, 2);
+        assert_eq!(ready.status_code(), StatusCode::OK);
+        assert_eq!(
+            serde_json::to_string(&ready).unwrap(),
+            r#"{"status":"ready","keys":2}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn readiness_without_keys() {
+        let ready = readiness(&HashMap::new(), None).await;
+        assert_eq!(ready.status, "unavailable");
+        assert_eq!(ready.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn check_unreachable_upstream() {
+        // Bind and drop a listener to find a port that refuses connections
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let status = check_upstream("127.0.0.1".to_string(), port, Duration::from_secs(1)).await;
+        assert!(!status.reachable);
+        assert!(status.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn check_reachable_upstream() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let status = check_upstream("127.0.0.1".to_string(), port, Duration::from_secs(1)).await;
+        assert_eq!(
+            status,
+            UpstreamStatus {
+                host: "127.0.0.1".to_string(),
+                port,
+                reachable: true,
+                error: None,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn upstream_checks_are_cached() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let keymap = dsn::make_key_map(
+            vec![KeyRing {
+                inbound: Some("https://abcdef@sentry.io/1234".to_string()),
+                outbound: vec![Some(
+                    format!("http://ghijkl@127.0.0.1:{port}/567")
+                        .as_str()
+                        .into(),
+                )],
+                spool: None,
+                allowed_origins: None,
+            }],
+            None,
+        )
+        .unwrap();
+        let checker = UpstreamChecker::new(Duration::from_secs(1));
+
+        let ready = readiness(&keymap, Some(&checker)).await;
+        assert_eq!(ready.status, "ready");
+        assert_eq!(ready.upstreams[0].port, port);
+
+        // The closed listener isn't checked again until the result expires
+        drop(listener);
+        let ready = readiness(&keymap, Some(&checker)).await;
+        assert_eq!(ready.status, "ready");
+    }
+}
@@ -1,35 +1,87 @@
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
+use arc_swap::ArcSwap;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 use hyper::body::Incoming;
-use hyper::server::conn::http1;
 use hyper::service::service_fn;
 use hyper::Request;
-use hyper_util::rt::TokioIo;
-use log::info;
+use hyper_util::rt::{TokioExecutor, TokioIo, TokioTimer};
+use hyper_util::server::conn::auto;
+use log::{debug, error, info, warn};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
+mod admin;
+mod client;
+mod compression;
 mod config;
+mod cors;
 mod dsn;
+mod envelope;
+mod health;
+mod metrics;
+mod reload;
 mod request;
+mod retry;
+mod route;
+mod sampling;
+mod schedule;
 mod service;
+mod spool;
+#[cfg(test)]
+mod testutil;
+mod tls;
+mod validate;
+
+/// How long clients get to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long abandoned deliveries get to write to the spool after the shutdown deadline.
+const ABANDON_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
 struct Args {
     /// Path to the configuration file
-    #[arg(short, long)]
-    config: String,
+    #[arg(short, long, global = true)]
+    config: Option<String>,
 
     /// Whether or not to enable verbose logging
-    #[arg(short, long)]
+    #[arg(short, long, global = true)]
     verbose: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the mirror server. This is the default when no command is given.
+    Serve,
+    /// Check the configuration file and print the keys it contains.
+    /// Exits with a non-zero status if the configuration is invalid.
+    Validate,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     // Read command line options
     let args = Args::parse();
+    let config = match args.config {
+        Some(config) => config,
+        None => Args::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "the following required arguments were not provided:\n  --config <CONFIG>",
+            )
+            .exit(),
+    };
 
     // Config logging
     if args.verbose {
@@ -38,51 +90,296 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         simple_logger::init_with_level(log::Level::Info).unwrap();
     }
 
-    let config_path = Path::new(&args.config);
-    info!("Using configuration file {0}", args.config);
+    match args.command.unwrap_or(Command::Serve) {
+        Command::Serve => serve(&config).await,
+        Command::Validate => {
+            if !validate::run(Path::new(&config)) {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Run the mirror server with the configuration file at `config`.
+async fn serve(config: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let config_path = Path::new(config);
+    info!("Using configuration file {0}", config);
 
     // Parse the configuration file
     let configdata = match config::load_config(config_path) {
         Ok(keys) => keys,
-        Err(_) => {
-            println!("Invalid configuration file");
-            panic!("Could not parse configuration file");
+        Err(e) => {
+            error!("Invalid configuration file {0}: {1}", config, e);
+            std::process::exit(1);
         }
     };
 
-    let port = configdata
-        .port
-        .expect("Missing required configuration `port`");
+    let port = match configdata.port {
+        Some(port) => port,
+        None => {
+            error!("Missing required configuration `port`");
+            std::process::exit(1);
+        }
+    };
     let ip = configdata
         .ip
         .or_else(|| Some("127.0.0.1".to_string()))
         .unwrap();
 
+    // Create keymap that we need to match incoming requests
+    let keymap = match dsn::make_key_map(configdata.keys, configdata.retry.as_ref()) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            error!("Invalid configuration file {0}: {1}", config, e);
+            std::process::exit(1);
+        }
+    };
+
+    // TLS is terminated on the listener when certificates are configured
+    let tls = match configdata.tls {
+        Some(tls_config) => match tls::TlsReloader::new(tls_config.clone()) {
+            Ok(tls) => {
+                info!("Terminating TLS with certificate {0}", tls_config.cert_path);
+                Some(tls)
+            }
+            Err(e) => {
+                error!("Invalid TLS configuration: {0}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
     let addr = format!("{ip}:{port}");
     info!("Listening on {0}", addr);
     let listener = TcpListener::bind(addr).await?;
 
-    // Create keymap that we need to match incoming requests
-    let keymap = dsn::make_key_map(configdata.keys);
-    let arcmap = Arc::new(keymap);
+    // Metrics are served on a separate admin port
+    let metrics = Arc::new(metrics::Metrics::new());
+    if let Some(admin_port) = configdata.admin_port {
+        let admin_addr = format!("{ip}:{admin_port}");
+        info!("Admin server listening on {0}", admin_addr);
+        let admin_listener = TcpListener::bind(admin_addr).await?;
+        tokio::task::spawn(admin::serve(admin_listener, metrics.clone()));
+    }
+
+    // One pooled client is shared by all outbound requests
+    let client_config = configdata.client.unwrap_or_default();
+    let client = client::OutboundClient::new(&client_config, metrics.clone())?;
 
+    // Cancelled when the server stops accepting connections
+    let shutdown = CancellationToken::new();
+    // Outbound deliveries and spool replays are drained on shutdown
+    let tasks = TaskTracker::new();
+
+    // Failed deliveries are spooled to disk and replayed in the background
+    let spool = match configdata.spool_path {
+        Some(spool_path) => {
+            info!("Spooling failed requests to {0}", spool_path);
+            let spool = Arc::new(spool::Spool::new(Path::new(&spool_path))?);
+            let interval = Duration::from_secs(configdata.spool_interval.unwrap_or(30));
+            tasks.spawn(spool::run(
+                spool.clone(),
+                client.clone(),
+                interval,
+                shutdown.clone(),
+            ));
+            Some(spool)
+        }
+        None => {
+            if keymap.values().any(|keyring| keyring.spool.is_some()) {
+                warn!("Keys have spool settings but `spool_path` is not set, spooling is disabled");
+            }
+            None
+        }
+    };
+
+    let arcmap: reload::SharedKeyMap = Arc::new(ArcSwap::from_pointee(keymap));
+
+    // Reload keys when the configuration changes
+    let reload_interval = Duration::from_secs(configdata.reload_interval.unwrap_or(5));
+    tokio::task::spawn(reload::watch(
+        config_path.to_path_buf(),
+        arcmap.clone(),
+        tls.clone(),
+        reload_interval,
+    ));
+
+    // Readiness checks can optionally connect to each outbound host
+    let health_config = configdata.health.unwrap_or_default();
+    let upstream_checks = if health_config.check_upstreams.unwrap_or(false) {
+        let timeout = Duration::from_millis(health_config.upstream_timeout.unwrap_or(2000));
+        Some(Arc::new(health::UpstreamChecker::new(timeout)))
+    } else {
+        None
+    };
+
+    let context = service::Context {
+        client,
+        metrics,
+        spool,
+        tasks,
+        abandon: CancellationToken::new(),
+        answer_early: configdata.answer_early.unwrap_or(false),
+        upstream_checks,
+    };
+
+    let shutdown_timeout = Duration::from_secs(configdata.shutdown_timeout.unwrap_or(30));
+    let mut terminate = signal(SignalKind::terminate())?;
+    let builder = Arc::new(server_builder(&configdata.server.unwrap_or_default()));
+    let connections = TaskTracker::new();
     loop {
-        let (stream, _) = listener.accept().await?;
-        let io = TokioIo::new(stream);
+        let stream = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    warn!("Could not accept connection: {0}", e);
+                    tokio::time::sleep(admin::ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            },
+            _ = tokio::signal::ctrl_c() => {
+                info!("Received SIGINT, shutting down");
+                break;
+            }
+            _ = terminate.recv() => {
+                info!("Received SIGTERM, shutting down");
+                break;
+            }
+        };
         let arcmap_loop = arcmap.clone();
+        let context_loop = context.clone();
+        let shutdown_loop = shutdown.clone();
+        let builder_loop = builder.clone();
+        let tls_loop = tls.as_ref().map(|tls| tls.current().load_full());
 
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new()
-                .serve_connection(
-                    io,
-                    service_fn(move |req: Request<Incoming>| {
-                        service::handle_request(req, arcmap_loop.clone())
-                    }),
+        connections.spawn(async move {
+            let Some(tls_config) = tls_loop else {
+                serve_connection(
+                    stream,
+                    &builder_loop,
+                    arcmap_loop,
+                    context_loop,
+                    shutdown_loop,
                 )
-                .await
-            {
-                eprintln!("Error serving connection: {:?}", err);
-            }
+                .await;
+                return;
+            };
+            let handshake = tokio::time::timeout(
+                TLS_HANDSHAKE_TIMEOUT,
+                TlsAcceptor::from(tls_config).accept(stream),
+            );
+            let stream = tokio::select! {
+                accepted = handshake => match accepted {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        debug!("TLS handshake failed: {0}", e);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("TLS handshake timed out");
+                        return;
+                    }
+                },
+                _ = shutdown_loop.cancelled() => return,
+            };
+            serve_connection(
+                stream,
+                &builder_loop,
+                arcmap_loop,
+                context_loop,
+                shutdown_loop,
+            )
+            .await;
         });
     }
+
+    // Stop accepting connections and let requests and deliveries that are still
+    // running finish until the shutdown deadline.
+    drop(listener);
+    shutdown.cancel();
+    connections.close();
+    context.tasks.close();
+    info!(
+        "Waiting up to {0} seconds for {1} connections and {2} deliveries",
+        shutdown_timeout.as_secs(),
+        connections.len(),
+        context.tasks.len()
+    );
+    let drained = tokio::time::timeout(shutdown_timeout, async {
+        connections.wait().await;
+        context.tasks.wait().await;
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            "Shutdown deadline reached, abandoning {0} connections and {1} deliveries",
+            connections.len(),
+            context.tasks.len()
+        );
+        // Abandoned deliveries are spooled when spooling is enabled
+        context.abandon.cancel();
+        if tokio::time::timeout(ABANDON_TIMEOUT, context.tasks.wait())
+            .await
+            .is_err()
+        {
+            warn!(
+                "Exiting with {0} deliveries still running",
+                context.tasks.len()
+            );
+        }
+    }
+    info!("Shutdown complete");
+
+    Ok(())
+}
+
+/// Serve requests on an inbound connection until the client closes it. The request
+/// in progress is finished and the connection is closed on shutdown.
+async fn serve_connection<I>(
+    stream: I,
+    builder: &auto::Builder<TokioExecutor>,
+    keymap: reload::SharedKeyMap,
+    context: service::Context,
+    shutdown: CancellationToken,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let conn = builder.serve_connection(
+        TokioIo::new(stream),
+        service_fn(move |req: Request<Incoming>| {
+            service::handle_request(req, keymap.load_full(), context.clone())
+        }),
+    );
+    tokio::pin!(conn);
+    let res = tokio::select! {
+        res = conn.as_mut() => res,
+        _ = shutdown.cancelled() => {
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(err) = res {
+        eprintln!("Error serving connection: {:?}", err);
+    }
+}
+
+/// Build the connection builder for inbound connections. Connections are served
+/// with HTTP/1.1, or HTTP/2 when the client starts with the HTTP/2 preface.
+fn server_builder(config: &config::ServerConfig) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .keep_alive(config.keep_alive.unwrap_or(true));
+    let mut http2 = builder.http2();
+    http2
+        .timer(TokioTimer::new())
+        .max_concurrent_streams(config.max_concurrent_streams.unwrap_or(200))
+        .keep_alive_interval(config.keep_alive_interval.map(Duration::from_millis));
+    if let Some(timeout) = config.keep_alive_timeout {
+        http2.keep_alive_timeout(Duration::from_millis(timeout));
+    }
+
+    builder
 }
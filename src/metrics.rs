@@ -0,0 +1,195 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, Opts, Registry, TextEncoder,
+};
+
+use hyper::StatusCode;
+
+/// Latency buckets in seconds for upstream requests.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Prometheus metrics for the mirror.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// Inbound requests by inbound public key and endpoint.
+    pub inbound_requests: IntCounterVec,
+    /// Inbound requests rejected because their DSN is not configured.
+    pub unknown_dsn: IntCounter,
+    /// Inbound requests rejected because their path is not an ingest endpoint.
+    pub unknown_endpoint: IntCounter,
+    /// Inbound request bodies that could not be decoded, by reason.
+    pub decode_failures: IntCounterVec,
+    /// Compressed envelopes that were forwarded as they were received, after
+    /// decompressing only their header.
+    pub decode_skipped: IntCounter,
+    /// Outbound request attempts, including retries, by host and status class.
+    pub outbound_attempts: IntCounterVec,
+    /// Outbound deliveries by host and result, after retries are exhausted.
+    pub outbound_deliveries: IntCounterVec,
+    /// Time to receive responses from upstreams, by host.
+    pub upstream_latency: HistogramVec,
+    /// Outbound DSNs that were activated or expired by their schedule, by host.
+    pub schedule_transitions: IntCounterVec,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("sentry_mirror".to_string()), None).unwrap();
+        let inbound_requests = IntCounterVec::new(
+            Opts::new("inbound_requests_total", "Inbound requests"),
+            &["inbound_key", "endpoint"],
+        )
+        .unwrap();
+        let unknown_dsn = IntCounter::new(
+            "unknown_dsn_total",
+            "Inbound requests rejected because their DSN is not configured",
+        )
+        .unwrap();
+        let unknown_endpoint = IntCounter::new(
+            "unknown_endpoint_total",
+            "Inbound requests rejected because their path is not an ingest endpoint",
+        )
+        .unwrap();
+        let decode_failures = IntCounterVec::new(
+            Opts::new(
+                "decode_failures_total",
+                "Inbound request bodies that could not be decoded",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        let decode_skipped = IntCounter::new(
+            "decode_skipped_total",
+            "Compressed envelopes forwarded after decompressing only the header",
+        )
+        .unwrap();
+        let outbound_attempts = IntCounterVec::new(
+            Opts::new("outbound_attempts_total", "Outbound request attempts"),
+            &["host", "status_class"],
+        )
+        .unwrap();
+        let outbound_deliveries = IntCounterVec::new(
+            Opts::new(
+                "outbound_deliveries_total",
+                "Outbound deliveries after retries",
+            ),
+            &["host", "result"],
+        )
+        .unwrap();
+        let upstream_latency = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_latency_seconds",
+                "Time to receive responses from upstreams",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["host"],
+        )
+        .unwrap();
+        let schedule_transitions = IntCounterVec::new(
+            Opts::new(
+                "outbound_schedule_transitions_total",
+                "Outbound DSNs activated or expired by their schedule",
+            ),
+            &["host", "transition"],
+        )
+        .unwrap();
+
+        registry
+            .register(Box::new(inbound_requests.clone()))
+            .unwrap();
+        registry.register(Box::new(unknown_dsn.clone())).unwrap();
+        registry
+            .register(Box::new(unknown_endpoint.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(decode_failures.clone()))
+            .unwrap();
+        registry.register(Box::new(decode_skipped.clone())).unwrap();
+        registry
+            .register(Box::new(outbound_attempts.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(outbound_deliveries.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(upstream_latency.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(schedule_transitions.clone()))
+            .unwrap();
+
+        Metrics {
+            registry,
+            inbound_requests,
+            unknown_dsn,
+            unknown_endpoint,
+            decode_failures,
+            decode_skipped,
+            outbound_attempts,
+            outbound_deliveries,
+            upstream_latency,
+            schedule_transitions,
+        }
+    }
+
+    /// Render all metrics in the prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        let encoder = TextEncoder::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buffer) {
+            log::warn!("Could not encode metrics: {0}", e);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+/// Get the status class label for an outbound attempt. `None` is a request
+/// that failed without a response.
+pub fn status_class(status: Option<StatusCode>) -> &'static str {
+    match status.map(|s| s.as_u16() / 100) {
+        Some(1) => "1xx",
+        Some(2) => "2xx",
+        Some(3) => "3xx",
+        Some(4) => "4xx",
+        Some(5) => "5xx",
+        Some(_) => "other",
+        None => "error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_classes() {
+        assert_eq!(status_class(Some(StatusCode::OK)), "2xx");
+        assert_eq!(status_class(Some(StatusCode::TOO_MANY_REQUESTS)), "4xx");
+        assert_eq!(status_class(Some(StatusCode::BAD_GATEWAY)), "5xx");
+        assert_eq!(status_class(None), "error");
+    }
+
+    #[test]
+    fn render_metrics() {
+        let metrics = Metrics::new();
+        metrics
+            .inbound_requests
+            .with_label_values(&["abcdef", "envelope"])
+            .inc();
+        metrics.unknown_dsn.inc();
+
+        let rendered = metrics.render();
+        assert!(rendered.contains(
+            r#"sentry_mirror_inbound_requests_total{endpoint="envelope",inbound_key="abcdef"} 1"#
+        ));
+        assert!(rendered.contains("sentry_mirror_unknown_dsn_total 1"));
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use arc_swap::ArcSwap;
+use log::{debug, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config;
+use crate::dsn;
+use crate::tls::TlsReloader;
+
+/// The keymap shared by all connections. Requests load the current map when they
+/// start, so in-flight requests finish with the map they started with.
+pub type SharedKeyMap = Arc<ArcSwap<HashMap<String, dsn::DsnKeyRing>>>;
+
+/// Load the configuration file at `path` and build a keymap from it.
+pub fn load_key_map(path: &Path) -> Result<HashMap<String, dsn::DsnKeyRing>, config::ConfigError> {
+    let configdata = config::load_config(path)?;
+
+    dsn::make_key_map(configdata.keys, configdata.retry.as_ref())
+}
+
+/// Reload the keymap from `path`. Invalid configuration is logged and the current
+/// keymap is kept.
+pub fn reload(path: &Path, keymap: &ArcSwap<HashMap<String, dsn::DsnKeyRing>>) {
+    match load_key_map(path) {
+        Ok(new_keymap) => {
+            info!(
+                "Reloaded configuration file {0} with {1} keys",
+                path.display(),
+                new_keymap.len()
+            );
+            keymap.store(Arc::new(new_keymap));
+        }
+        Err(e) => {
+            warn!(
+                "Could not reload configuration file {0}, keeping the current configuration: {1}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Reload the keymap when the process receives SIGHUP, or when the configuration
+/// file is modified. The file is checked for changes every `interval`.
+/// TLS certificates are reloaded on SIGHUP.
+pub async fn watch(
+    path: PathBuf,
+    keymap: SharedKeyMap,
+    tls: Option<TlsReloader>,
+    interval: Duration,
+) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(
+                "Could not listen for SIGHUP, configuration will not be reloaded: {0}",
+                e
+            );
+            return;
+        }
+    };
+    let mut last_modified = modified(&path);
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                info!("Received SIGHUP, reloading configuration");
+                last_modified = modified(&path);
+                if let Some(tls) = &tls {
+                    tls.reload();
+                }
+            }
+            _ = ticker.tick() => {
+                let current = modified(&path);
+                if current == last_modified {
+                    continue;
+                }
+                debug!("Configuration file {0} changed", path.display());
+                last_modified = current;
+            }
+        }
+        reload(&path, &keymap);
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    fn config(public_key: &str) -> String {
+        format!(
+            "port: 3000\nkeys:\n  - inbound: http://{public_key}@localhost/1\n    outbound:\n      - https://outbound@o1.ingest.sentry.io/2\n"
+        )
+    }
+
+    #[tokio::test]
+    async fn watch_reloads_changed_file() {
+        let dir = TempDir::new("reload");
+        let path = dir.write("config.yml", &config("abcdef"));
+        let keymap: SharedKeyMap = Arc::new(ArcSwap::from_pointee(load_key_map(&path).unwrap()));
+        let watcher = tokio::spawn(watch(
+            path.clone(),
+            keymap.clone(),
+            None,
+            Duration::from_millis(10),
+        ));
+
+        // Let the watcher read the modification time before changing the file
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(&path, config("ghijkl")).unwrap();
+        for _ in 0..200 {
+            if keymap.load().contains_key("ghijkl") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        watcher.abort();
+        assert!(keymap.load().contains_key("ghijkl"));
+        assert!(!keymap.load().contains_key("abcdef"));
+    }
+
+    #[test]
+    fn reload_keeps_keymap_when_invalid() {
+        let dir = TempDir::new("reload-invalid");
+        let path = dir.write("config.yml", &config("abcdef"));
+        let keymap = ArcSwap::from_pointee(load_key_map(&path).unwrap());
+
+        std::fs::write(&path, "port: 3000\nkeys:\n  - inbound: [\n").unwrap();
+        reload(&path, &keymap);
+        assert!(keymap.load().contains_key("abcdef"));
+    }
+}
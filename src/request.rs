@@ -1,15 +1,18 @@
 use flate2::read::{DeflateDecoder, GzDecoder};
 use hyper::body::Bytes;
-use hyper::header::HeaderValue;
+use hyper::header::{HeaderValue, CONTENT_TYPE};
 use hyper::http::request::Builder as RequestBuilder;
 use hyper::http::uri::PathAndQuery;
 use hyper::{HeaderMap, Request, Uri};
 use log::warn;
 use regex::Regex;
 use serde_json::Value;
+use std::fmt;
 use std::io::prelude::*;
+use std::io::BufReader;
 
 use crate::dsn;
+use crate::route::Route;
 
 /// Several headers should not be forwarded as they can cause data truncation, or incorrect behavior.
 const NO_COPY_HEADERS: [&str; 4] = [
@@ -27,14 +30,11 @@ pub fn make_outbound_request(
     headers: &HeaderMap,
     outbound: &dsn::Dsn,
 ) -> RequestBuilder {
-    // Update project id in the path
-    let mut new_path = uri.path().to_string();
-    let path_parts: Vec<_> = uri.path().split('/').filter(|i| !i.is_empty()).collect();
-    if path_parts.len() == 3 && path_parts[0] == "api" {
-        let original_projectid = path_parts[1];
-        let new_project_id = outbound.project_id.clone();
-        new_path = new_path.replace(original_projectid, &new_project_id);
-    }
+    // Update the project id and public key in the path
+    let new_path = match Route::parse(uri.path()) {
+        Ok(route) => route.outbound_path(outbound),
+        Err(_) => uri.path().to_string(),
+    };
     // Replace public keys in the query string
     let query = match uri.query() {
         Some(value) => replace_public_key(value, outbound),
@@ -60,7 +60,7 @@ pub fn make_outbound_request(
             continue;
         }
         if key == dsn::AUTHORIZATION_HEADER || key == dsn::SENTRY_X_AUTH_HEADER {
-            let updated_value = replace_public_key(value.to_str().unwrap(), outbound);
+            let updated_value = replace_auth_header(value.to_str().unwrap(), outbound);
             outbound_headers.insert(key, updated_value.parse().unwrap());
         } else {
             outbound_headers.insert(key, value.clone());
@@ -70,6 +70,40 @@ pub fn make_outbound_request(
     builder
 }
 
+/// Send a request made by `make_outbound_request` for the store endpoint to the
+/// envelope endpoint instead.
+pub fn store_to_envelope_request(mut builder: RequestBuilder) -> RequestBuilder {
+    if let Some(uri) = builder.uri_ref() {
+        let path = uri.path().replacen("/store", "/envelope", 1);
+        let mut parts = uri.clone().into_parts();
+        parts.path_and_query = match uri.query() {
+            Some(query) => format!("{path}?{query}").parse().ok(),
+            None => path.parse().ok(),
+        };
+        if let Ok(new_uri) = Uri::from_parts(parts) {
+            builder = builder.uri(new_uri);
+        }
+    }
+    if let Some(headers) = builder.headers_mut() {
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/x-sentry-envelope"),
+        );
+    }
+
+    builder
+}
+
+/// Replace the project id in a legacy store event. Old SDKs include the project
+/// of their DSN in the event, newer ones don't, and the event is unchanged.
+pub fn replace_store_project(body: &Bytes, outbound: &dsn::Dsn) -> Option<Bytes> {
+    let mut event: Value = serde_json::from_slice(body).ok()?;
+    let project = event.get_mut("project")?;
+    *project = Value::String(outbound.project_id.clone());
+
+    serde_json::to_vec(&event).ok().map(Bytes::from)
+}
+
 /// Replace the DSN key if it is found in the first line of the body
 /// as per the envelope specs https://develop.sentry.dev/sdk/envelopes/
 pub fn replace_envelope_dsn(body: &Bytes, outbound: &dsn::Dsn) -> Option<Bytes> {
@@ -119,6 +153,15 @@ pub fn replace_envelope_dsn(body: &Bytes, outbound: &dsn::Dsn) -> Option<Bytes>
     Some(new_body)
 }
 
+/// Replace the public key in an auth header, or the DSN in `Authorization: DSN <dsn>`
+/// headers used by cron check-ins.
+fn replace_auth_header(value: &str, outbound: &dsn::Dsn) -> String {
+    if value.starts_with(dsn::DSN_AUTH_PREFIX) {
+        return format!("{0}{1}", dsn::DSN_AUTH_PREFIX, outbound);
+    }
+    replace_public_key(value, outbound)
+}
+
 fn replace_public_key(target: &str, outbound: &dsn::Dsn) -> String {
     let pattern = Regex::new(r"sentry_key=([a-f0-9]+)").unwrap();
     let public_key = &outbound.public_key;
@@ -130,39 +173,80 @@ fn replace_public_key(target: &str, outbound: &dsn::Dsn) -> String {
 
 #[derive(Debug)]
 pub enum BodyError {
-    UnsupportedCodec,
+    UnsupportedCodec(String),
     CouldNotDecode(std::io::Error),
     InvalidHeader,
 }
 
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BodyError::UnsupportedCodec(codec) => {
+                write!(f, "unsupported content-encoding `{codec}`")
+            }
+            BodyError::CouldNotDecode(e) => write!(f, "could not decode body: {e}"),
+            BodyError::InvalidHeader => write!(f, "invalid content-encoding header"),
+        }
+    }
+}
+
 /// Decode compressed body into hyper::Bytes
 pub fn decode_body(encoding_header: &HeaderValue, body: &Bytes) -> Result<Bytes, BodyError> {
-    let encoding_value = match encoding_header.to_str() {
-        Ok(value) => value,
-        Err(_) => return Err(BodyError::InvalidHeader),
-    };
+    let mut decoder = decoder(encoding_header, body)?;
     let mut decompressed = Vec::with_capacity(8 * 1024);
-    let body_vec = body.to_vec();
-
-    if encoding_value == "gzip" {
-        let mut decoder = GzDecoder::new(body_vec.as_slice());
+    decoder
+        .read_to_end(&mut decompressed)
+        .map_err(BodyError::CouldNotDecode)?;
 
-        decoder
-            .read_to_end(&mut decompressed)
-            .map_err(BodyError::CouldNotDecode)?;
+    Ok(Bytes::from(decompressed))
+}
 
-        Ok(Bytes::from(decompressed))
-    } else if encoding_value == "deflate" {
-        let mut decoder = DeflateDecoder::new(body_vec.as_slice());
+/// Decode the first line of a compressed body, without the newline, and without
+/// decompressing the rest of the body. Returns `None` when the line is longer than `limit`.
+pub fn decode_first_line(
+    encoding_header: &HeaderValue,
+    body: &Bytes,
+    limit: usize,
+) -> Result<Option<Bytes>, BodyError> {
+    let decoder = decoder(encoding_header, body)?;
+    let mut line = Vec::new();
+    BufReader::new(decoder.take(limit as u64 + 1))
+        .read_until(b'\n', &mut line)
+        .map_err(BodyError::CouldNotDecode)?;
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > limit {
+        return Ok(None);
+    }
 
-        decoder
-            .read_to_end(&mut decompressed)
-            .map_err(BodyError::CouldNotDecode)?;
+    Ok(Some(Bytes::from(line)))
+}
 
-        Ok(Bytes::from(decompressed))
-    } else {
-        Err(BodyError::UnsupportedCodec)
+/// Create a reader that decodes a body with the encodings in a content-encoding header.
+fn decoder<'a>(
+    encoding_header: &HeaderValue,
+    body: &'a [u8],
+) -> Result<Box<dyn Read + 'a>, BodyError> {
+    let encoding_value = match encoding_header.to_str() {
+        Ok(value) => value,
+        Err(_) => return Err(BodyError::InvalidHeader),
+    };
+    // Encodings are listed in the order they were applied, so they are decoded in reverse
+    let mut reader: Box<dyn Read + 'a> = Box::new(body);
+    for encoding in encoding_value.rsplit(',').map(str::trim) {
+        reader = match encoding.to_ascii_lowercase().as_str() {
+            "" | "identity" => reader,
+            "gzip" | "x-gzip" => Box::new(GzDecoder::new(reader)),
+            "deflate" => Box::new(DeflateDecoder::new(reader)),
+            "br" => Box::new(brotli::Decompressor::new(reader, 4096)),
+            "zstd" => Box::new(
+                zstd::stream::read::Decoder::new(reader).map_err(BodyError::CouldNotDecode)?,
+            ),
+            encoding => return Err(BodyError::UnsupportedCodec(encoding.to_string())),
+        };
     }
+
+    Ok(reader)
 }
 
 #[cfg(test)]
@@ -306,6 +390,106 @@ mod tests {
         assert_eq!(uri, "https://o789.ingest.sentry.io/api/6789/envelope/");
     }
 
+    #[test]
+    fn make_outbound_request_store_paths() {
+        let outbound: dsn::Dsn = "https://outbound@o789.ingest.sentry.io/6789"
+            .parse()
+            .unwrap();
+        let headers = HeaderMap::new();
+        for (path, expected) in [
+            ("/api/1/store/", "/api/6789/store/"),
+            ("/api/1/store", "/api/6789/store"),
+            ("/api/store/", "/api/6789/store/"),
+            ("/api/store", "/api/6789/store"),
+            ("/api/11/envelope/", "/api/6789/envelope/"),
+        ] {
+            let uri: Uri = format!("https://o123.ingest.sentry.io{path}?sentry_key=abcdef")
+                .parse()
+                .unwrap();
+            let req = make_outbound_request(&uri, &headers, &outbound)
+                .body("")
+                .unwrap();
+            assert_eq!(
+                req.uri().to_string(),
+                format!("https://o789.ingest.sentry.io{expected}?sentry_key=outbound")
+            );
+        }
+    }
+
+    #[test]
+    fn make_outbound_request_cron_check_in() {
+        let outbound: dsn::Dsn = "https://outbound@o789.ingest.sentry.io/6789"
+            .parse()
+            .unwrap();
+        let uri: Uri = "https://o123.ingest.sentry.io/api/1/cron/my-job/abcdef/?status=ok"
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "Authorization",
+            "DSN https://abcdef@o123.ingest.sentry.io/1"
+                .parse()
+                .unwrap(),
+        );
+
+        let req = make_outbound_request(&uri, &headers, &outbound)
+            .body("")
+            .unwrap();
+        assert_eq!(
+            req.uri(),
+            "https://o789.ingest.sentry.io/api/6789/cron/my-job/outbound/?status=ok"
+        );
+        assert_eq!(
+            req.headers().get("Authorization").unwrap(),
+            "DSN https://outbound@o789.ingest.sentry.io/6789"
+        );
+    }
+
+    #[test]
+    fn test_store_to_envelope_request() {
+        let outbound: dsn::Dsn = "https://outbound@o789.ingest.sentry.io/6789"
+            .parse()
+            .unwrap();
+        let uri: Uri = "https://o123.ingest.sentry.io/api/store/?sentry_key=abcdef"
+            .parse()
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", "application/json".parse().unwrap());
+        headers.insert("Origin", "example.com".parse().unwrap());
+
+        let builder = make_outbound_request(&uri, &headers, &outbound);
+        let req = store_to_envelope_request(builder).body("").unwrap();
+        assert_eq!(
+            req.uri(),
+            "https://o789.ingest.sentry.io/api/6789/envelope/?sentry_key=outbound"
+        );
+        assert_eq!(
+            req.headers()
+                .get_all("Content-Type")
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["application/x-sentry-envelope"]
+        );
+        assert!(req.headers().contains_key("Origin"));
+    }
+
+    #[test]
+    fn test_replace_store_project() {
+        let outbound: dsn::Dsn = "https://outbound@o789.ingest.sentry.io/6789"
+            .parse()
+            .unwrap();
+        let body = Bytes::from(r#"{"project":"1","message":"hello"}"#);
+        assert_eq!(
+            replace_store_project(&body, &outbound).unwrap(),
+            Bytes::from(r#"{"message":"hello","project":"6789"}"#)
+        );
+
+        let body = Bytes::from(r#"{"message":"hello"}"#);
+        assert!(replace_store_project(&body, &outbound).is_none());
+        let body = Bytes::from("eJyrVkrOz0nNVbJSUAIAK9AEdQ==");
+        assert!(replace_store_project(&body, &outbound).is_none());
+    }
+
     #[test]
     fn test_replace_envelope_dsn_empty_body() {
         let outbound: dsn::Dsn = "https://outbound@o789.ingest.sentry.io/6789"
@@ -444,6 +628,81 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_decode_body_brotli_and_zstd() {
+        let contents = b"some content to be compressed";
+        let mut brotli_out = Vec::new();
+        brotli::CompressorReader::new(&contents[..], 4096, 5, 22)
+            .read_to_end(&mut brotli_out)
+            .unwrap();
+        let zstd_out = zstd::encode_all(&contents[..], 3).unwrap();
+
+        for (header, body) in [("br", brotli_out), ("zstd", zstd_out)] {
+            let header_val: HeaderValue = header.parse().unwrap();
+            let decoded = decode_body(&header_val, &Bytes::from(body)).unwrap();
+            assert_eq!(&decoded[..], contents, "{header}");
+        }
+    }
+
+    #[test]
+    fn test_decode_body_stacked() {
+        let contents = b"some content to be compressed";
+        let mut gzip_out = Vec::new();
+        GzEncoder::new(&contents[..], Compression::fast())
+            .read_to_end(&mut gzip_out)
+            .unwrap();
+        // gzip was applied first, then zstd
+        let zstd_out = zstd::encode_all(&gzip_out[..], 3).unwrap();
+
+        let header_val: HeaderValue = "GZIP, identity,Zstd".parse().unwrap();
+        let decoded = decode_body(&header_val, &Bytes::from(zstd_out)).unwrap();
+        assert_eq!(&decoded[..], contents);
+    }
+
+    #[test]
+    fn test_decode_body_unsupported() {
+        let bytes = Bytes::from("some content");
+        let header_val: HeaderValue = "gzip, compress".parse().unwrap();
+        let err = decode_body(&header_val, &bytes).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported content-encoding `compress`");
+    }
+
+    #[test]
+    fn test_decode_first_line() {
+        let contents =
+            b"{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}\n{\"type\":\"event\"}\n{}\n";
+        let mut gzip_out = Vec::new();
+        GzEncoder::new(&contents[..], Compression::fast())
+            .read_to_end(&mut gzip_out)
+            .unwrap();
+        let body = Bytes::from(gzip_out);
+        let header_val: HeaderValue = "gzip".parse().unwrap();
+
+        let line = decode_first_line(&header_val, &body, 1024).unwrap();
+        assert_eq!(
+            line.unwrap(),
+            r#"{"event_id":"9ec79c33ec9942ab8353589fcb2e04dc"}"#
+        );
+        // The line is exactly at the limit
+        let line = decode_first_line(&header_val, &body, 47).unwrap();
+        assert_eq!(line.unwrap().len(), 47);
+        assert_eq!(decode_first_line(&header_val, &body, 46).unwrap(), None);
+        // The first line of a truncated body can be read
+        let truncated = body.slice(..body.len() - 10);
+        assert!(decode_first_line(&header_val, &truncated, 1024)
+            .unwrap()
+            .is_some());
+
+        // Bodies without a newline are a single line
+        let body = Bytes::from(zstd::encode_all(&b"{}"[..], 3).unwrap());
+        let header_val: HeaderValue = "zstd".parse().unwrap();
+        let line = decode_first_line(&header_val, &body, 1024).unwrap();
+        assert_eq!(line.unwrap(), "{}");
+
+        let header_val: HeaderValue = "compress".parse().unwrap();
+        assert!(decode_first_line(&header_val, &body, 1024).is_err());
+    }
+
     fn string_list_to_bytes(lines: Vec<&str>) -> Bytes {
         let joined = lines.join("\n");
 
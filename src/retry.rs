@@ -0,0 +1,269 @@
+use std::time::{Duration, SystemTime};
+
+use hyper::{HeaderMap, StatusCode};
+
+use crate::config::RetryConfig;
+
+pub const RETRY_AFTER_HEADER: &str = "retry-after";
+pub const RATE_LIMITS_HEADER: &str = "x-sentry-rate-limits";
+
+/// Resolved retry settings for an outbound DSN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// The delay before the first retry. Each following retry doubles the delay.
+    pub base_backoff: Duration,
+    /// The longest delay between attempts.
+    pub max_backoff: Duration,
+    /// Whether or not delays are randomized.
+    pub jitter: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(10_000),
+            jitter: true,
+        }
+    }
+}
+
+impl From<&RetryConfig> for RetryPolicy {
+    fn from(config: &RetryConfig) -> Self {
+        let defaults = RetryPolicy::default();
+        RetryPolicy {
+            max_attempts: config.max_attempts.unwrap_or(defaults.max_attempts).max(1),
+            base_backoff: config
+                .base_backoff
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.base_backoff),
+            max_backoff: config
+                .max_backoff
+                .map(Duration::from_millis)
+                .unwrap_or(defaults.max_backoff),
+            jitter: config.jitter.unwrap_or(defaults.jitter),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Get the exponential backoff to wait after `attempt` failed attempts.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self
+            .base_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff);
+        if self.jitter {
+            // Pick a delay between half the backoff and the full backoff.
+            let half = delay / 2;
+            half + half.mul_f64(fastrand::f64())
+        } else {
+            delay
+        }
+    }
+
+    /// Get the delay before making another attempt after `attempt` attempts have failed.
+    /// `None` means the request should not be retried. Delays requested by the upstream
+    /// take precedence over the backoff, and requests are not retried when the upstream
+    /// asks for a longer delay than `max_backoff`.
+    pub fn next_delay(
+        &self,
+        attempt: u32,
+        status: Option<StatusCode>,
+        headers: Option<&HeaderMap>,
+    ) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+        if let Some(status) = status {
+            if !is_retryable(status) {
+                return None;
+            }
+        }
+        let backoff = self.backoff(attempt);
+        match headers.and_then(upstream_delay) {
+            Some(delay) if delay > self.max_backoff => None,
+            Some(delay) => Some(delay.max(backoff)),
+            None => Some(backoff),
+        }
+    }
+}
+
+/// Whether or not a response status can be retried. 4xx responses other than 429
+/// are not going to succeed on another attempt.
+pub fn is_retryable(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+/// Find the delay an upstream requested with either `X-Sentry-Rate-Limits` or `Retry-After`.
+pub fn upstream_delay(headers: &HeaderMap) -> Option<Duration> {
+    if let Some(value) = headers.get(RATE_LIMITS_HEADER) {
+        if let Some(delay) = value.to_str().ok().and_then(parse_rate_limits) {
+            return Some(delay);
+        }
+    }
+    let value = headers.get(RETRY_AFTER_HEADER)?.to_str().ok()?;
+    parse_retry_after(value, SystemTime::now())
+}
+
+/// Parse a `Retry-After` header value. Values can be either seconds or an HTTP date.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        if seconds.is_finite() && seconds >= 0.0 {
+            return Some(Duration::from_secs_f64(seconds));
+        }
+        return None;
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+/// Parse an `X-Sentry-Rate-Limits` header and get the longest delay in it.
+/// The header is a comma separated list of `retry_after:categories:scope:...` limits.
+/// See https://develop.sentry.dev/sdk/rate-limiting/
+fn parse_rate_limits(value: &str) -> Option<Duration> {
+    value
+        .split(',')
+        .filter_map(|limit| limit.trim().split(':').next())
+        .filter_map(|retry_after| retry_after.trim().parse::<f64>().ok())
+        .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+        .map(Duration::from_secs_f64)
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(1000),
+            jitter: false,
+        }
+    }
+
+    #[test]
+    fn from_config_defaults() {
+        let policy = RetryPolicy::from(&RetryConfig {
+            max_attempts: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(policy.max_attempts, 3);
+        assert_eq!(policy.base_backoff, Duration::from_millis(100));
+        assert_eq!(policy.max_backoff, Duration::from_millis(10_000));
+        assert!(policy.jitter);
+    }
+
+    #[test]
+    fn backoff_exponential_and_capped() {
+        let policy = policy(10);
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(5), Duration::from_millis(1000));
+        assert_eq!(policy.backoff(40), Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn backoff_jitter_in_range() {
+        let policy = RetryPolicy {
+            jitter: true,
+            ..policy(10)
+        };
+        for _ in 0..20 {
+            let delay = policy.backoff(2);
+            assert!(delay >= Duration::from_millis(100));
+            assert!(delay <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn next_delay_attempts_exhausted() {
+        let policy = policy(2);
+        assert!(policy.next_delay(1, None, None).is_some());
+        assert!(policy.next_delay(2, None, None).is_none());
+    }
+
+    #[test]
+    fn next_delay_client_errors() {
+        let policy = policy(3);
+        assert!(policy
+            .next_delay(1, Some(StatusCode::BAD_REQUEST), None)
+            .is_none());
+        assert!(policy
+            .next_delay(1, Some(StatusCode::FORBIDDEN), None)
+            .is_none());
+        assert!(policy
+            .next_delay(1, Some(StatusCode::TOO_MANY_REQUESTS), None)
+            .is_some());
+        assert!(policy
+            .next_delay(1, Some(StatusCode::BAD_GATEWAY), None)
+            .is_some());
+    }
+
+    #[test]
+    fn next_delay_honours_retry_after() {
+        let policy = policy(3);
+        let mut headers = HeaderMap::new();
+        headers.insert("Retry-After", "1".parse().unwrap());
+        let delay = policy.next_delay(1, Some(StatusCode::TOO_MANY_REQUESTS), Some(&headers));
+        assert_eq!(delay, Some(Duration::from_secs(1)));
+
+        headers.insert("Retry-After", "60".parse().unwrap());
+        let delay = policy.next_delay(1, Some(StatusCode::TOO_MANY_REQUESTS), Some(&headers));
+        assert!(delay.is_none(), "longer than max_backoff");
+    }
+
+    #[test]
+    fn upstream_delay_prefers_rate_limits() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Retry-After", "5".parse().unwrap());
+        headers.insert(
+            "X-Sentry-Rate-Limits",
+            "60:transaction:key, 2700:default;error;security:organization"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(upstream_delay(&headers), Some(Duration::from_secs(2700)));
+    }
+
+    #[test]
+    fn parse_retry_after_values() {
+        let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+        assert_eq!(parse_retry_after("-1", now), None);
+    }
+
+    #[test]
+    fn parse_rate_limits_values() {
+        assert_eq!(
+            parse_rate_limits("60::organization"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_rate_limits("60:transaction:key,10:error:project"),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(parse_rate_limits("garbage"), None);
+    }
+}
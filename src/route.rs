@@ -0,0 +1,235 @@
+use std::fmt;
+
+use crate::dsn;
+
+/// Ingest endpoints that requests are mirrored for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Endpoint {
+    Envelope,
+    Store,
+    Minidump,
+    Security,
+    CspReport,
+    Unreal,
+    Cron,
+    Otlp,
+}
+
+impl Endpoint {
+    /// The name of the endpoint in ingest paths, also used for metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Endpoint::Envelope => "envelope",
+            Endpoint::Store => "store",
+            Endpoint::Minidump => "minidump",
+            Endpoint::Security => "security",
+            Endpoint::CspReport => "csp-report",
+            Endpoint::Unreal => "unreal",
+            Endpoint::Cron => "cron",
+            Endpoint::Otlp => "otlp",
+        }
+    }
+}
+
+/// A path that isn't one of the ingest endpoints.
+#[derive(Debug, PartialEq)]
+pub struct UnknownRoute {
+    pub path: String,
+}
+
+impl fmt::Display for UnknownRoute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{0}` is not a supported ingest endpoint", self.path)
+    }
+}
+
+impl std::error::Error for UnknownRoute {}
+
+/// An ingest path split into segments, with the positions of the segments that
+/// are rewritten for outbound DSNs.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub endpoint: Endpoint,
+    segments: Vec<String>,
+    /// The position of the project id. Unset for legacy store requests.
+    project: Option<usize>,
+    /// The position of the public key, for endpoints that have it in the path.
+    key: Option<usize>,
+    trailing_slash: bool,
+}
+
+impl Route {
+    /// Match a request path with the ingest endpoints:
+    ///
+    /// - `/api/<project>/envelope/`
+    /// - `/api/<project>/store/` and `/api/store/`
+    /// - `/api/<project>/minidump/`
+    /// - `/api/<project>/security/`
+    /// - `/api/<project>/csp-report/`
+    /// - `/api/<project>/unreal/<key>/`
+    /// - `/api/<project>/cron/<slug>/` and `/api/<project>/cron/<slug>/<key>/`
+    /// - `/api/<project>/otlp/...`
+    pub fn parse(path: &str) -> Result<Route, UnknownRoute> {
+        let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+        let (endpoint, project, key) = match segments.as_slice() {
+            ["api", "store"] => (Endpoint::Store, None, None),
+            ["api", project, rest @ ..] if is_project_id(project) => {
+                let (endpoint, key) = match rest {
+                    ["envelope"] => (Endpoint::Envelope, None),
+                    ["store"] => (Endpoint::Store, None),
+                    ["minidump"] => (Endpoint::Minidump, None),
+                    ["security"] => (Endpoint::Security, None),
+                    ["csp-report"] => (Endpoint::CspReport, None),
+                    ["unreal", _] => (Endpoint::Unreal, Some(3)),
+                    ["cron", _] => (Endpoint::Cron, None),
+                    ["cron", _, _] => (Endpoint::Cron, Some(4)),
+                    ["otlp", _, ..] => (Endpoint::Otlp, None),
+                    _ => return Err(unknown(path)),
+                };
+                (endpoint, Some(1), key)
+            }
+            _ => return Err(unknown(path)),
+        };
+
+        Ok(Route {
+            endpoint,
+            segments: segments.into_iter().map(String::from).collect(),
+            project,
+            key,
+            trailing_slash: path.ends_with('/'),
+        })
+    }
+
+    /// Get the public key in the path of unreal and cron requests.
+    pub fn public_key(&self) -> Option<&str> {
+        self.key.map(|key| self.segments[key].as_str())
+    }
+
+    /// Get the path for an outbound DSN. Only the project id and public key segments
+    /// are replaced. Legacy store paths get the project id of the outbound DSN.
+    pub fn outbound_path(&self, outbound: &dsn::Dsn) -> String {
+        let mut segments = self.segments.clone();
+        match self.project {
+            Some(project) => segments[project] = outbound.project_id.clone(),
+            None => segments.insert(1, outbound.project_id.clone()),
+        }
+        if let Some(key) = self.key {
+            segments[key] = outbound.public_key.clone();
+        }
+        let mut path = format!("/{0}", segments.join("/"));
+        if self.trailing_slash {
+            path.push('/');
+        }
+        path
+    }
+}
+
+fn unknown(path: &str) -> UnknownRoute {
+    UnknownRoute {
+        path: path.to_string(),
+    }
+}
+
+fn is_project_id(segment: &str) -> bool {
+    segment.bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outbound() -> dsn::Dsn {
+        "https://outbound@o789.ingest.sentry.io/6789"
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn rewrite_project_ids() {
+        for (path, endpoint, expected) in [
+            (
+                "/api/1/envelope/",
+                Endpoint::Envelope,
+                "/api/6789/envelope/",
+            ),
+            ("/api/11/envelope", Endpoint::Envelope, "/api/6789/envelope"),
+            ("/api/1/store/", Endpoint::Store, "/api/6789/store/"),
+            ("/api/store/", Endpoint::Store, "/api/6789/store/"),
+            (
+                "/api/1/minidump/",
+                Endpoint::Minidump,
+                "/api/6789/minidump/",
+            ),
+            (
+                "/api/1/security/",
+                Endpoint::Security,
+                "/api/6789/security/",
+            ),
+            (
+                "/api/1/csp-report/",
+                Endpoint::CspReport,
+                "/api/6789/csp-report/",
+            ),
+            (
+                "/api/1/otlp/v1/traces/",
+                Endpoint::Otlp,
+                "/api/6789/otlp/v1/traces/",
+            ),
+            // Only the project id segment is replaced
+            ("/api/1/cron/1/", Endpoint::Cron, "/api/6789/cron/1/"),
+        ] {
+            let route = Route::parse(path).unwrap();
+            assert_eq!(route.endpoint, endpoint, "{path}");
+            assert_eq!(route.outbound_path(&outbound()), expected);
+            assert_eq!(route.public_key(), None);
+        }
+    }
+
+    #[test]
+    fn rewrite_path_keys() {
+        let route = Route::parse("/api/1/unreal/abcdef/").unwrap();
+        assert_eq!(route.endpoint, Endpoint::Unreal);
+        assert_eq!(route.public_key(), Some("abcdef"));
+        assert_eq!(
+            route.outbound_path(&outbound()),
+            "/api/6789/unreal/outbound/"
+        );
+
+        let route = Route::parse("/api/1/cron/abcdef/abcdef/").unwrap();
+        assert_eq!(route.endpoint, Endpoint::Cron);
+        assert_eq!(route.public_key(), Some("abcdef"));
+        assert_eq!(
+            route.outbound_path(&outbound()),
+            "/api/6789/cron/abcdef/outbound/"
+        );
+    }
+
+    #[test]
+    fn reject_unknown_paths() {
+        for path in [
+            "/",
+            "/api/",
+            "/api/1/",
+            "/api/1/events/",
+            "/api/abc/envelope/",
+            "/api/1/envelope/extra/",
+            "/api/1/unreal/",
+            "/api/1/cron/",
+            "/api/1/otlp/",
+            "/api/store/extra/",
+            "/1/envelope/",
+        ] {
+            assert_eq!(
+                Route::parse(path),
+                Err(UnknownRoute {
+                    path: path.to_string()
+                }),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            Route::parse("/api/1/events/").unwrap_err().to_string(),
+            "`/api/1/events/` is not a supported ingest endpoint"
+        );
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::BTreeMap;
+
+/// Resolved sample rates for an outbound DSN.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SampleRates {
+    /// The fraction of requests forwarded to the DSN.
+    rate: f64,
+    /// Sample rates for envelope item types. Other item types use `rate`.
+    item_rates: BTreeMap<String, f64>,
+}
+
+impl Default for SampleRates {
+    fn default() -> Self {
+        SampleRates {
+            rate: 1.0,
+            item_rates: BTreeMap::new(),
+        }
+    }
+}
+
+impl SampleRates {
+    pub fn new(rate: Option<f64>, item_rates: Option<BTreeMap<String, f64>>) -> SampleRates {
+        SampleRates {
+            rate: rate.unwrap_or(1.0),
+            item_rates: item_rates.unwrap_or_default(),
+        }
+    }
+
+    /// Get the first rate that is not between 0.0 and 1.0.
+    pub fn invalid_rate(&self) -> Option<f64> {
+        std::iter::once(&self.rate)
+            .chain(self.item_rates.values())
+            .find(|rate| !(0.0..=1.0).contains(*rate))
+            .copied()
+    }
+
+    /// Whether or not everything is forwarded.
+    pub fn is_all(&self) -> bool {
+        self.rate >= 1.0 && self.item_rates.values().all(|rate| *rate >= 1.0)
+    }
+
+    /// Whether or not any item type has its own sample rate.
+    pub fn has_item_rates(&self) -> bool {
+        !self.item_rates.is_empty()
+    }
+
+    /// Get the sample rate for requests that aren't envelopes, or for items of
+    /// `item_type` when it is set.
+    pub fn rate(&self, item_type: Option<&str>) -> f64 {
+        item_type
+            .and_then(|item_type| self.item_rates.get(item_type))
+            .copied()
+            .unwrap_or(self.rate)
+    }
+
+    /// Whether or not to keep a request or item with the `sample` from [`sample`].
+    pub fn keep(&self, item_type: Option<&str>, sample: f64) -> bool {
+        sample < self.rate(item_type)
+    }
+}
+
+/// Get a sample between 0.0 and 1.0 for a request. Requests with the same `id` get
+/// the same sample, on every instance of the mirror, so that all items of a trace or
+/// event are kept or dropped together. Requests without an id are sampled randomly.
+pub fn sample(id: Option<&str>) -> f64 {
+    match id {
+        // Use the top 53 bits of the hash, which is all an f64 can represent exactly
+        Some(id) => (mix(fnv1a(id.as_bytes())) >> 11) as f64 / (1u64 << 53) as f64,
+        None => fastrand::f64(),
+    }
+}
+
+/// 64 bit FNV-1a, which is stable across processes and platforms.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Spread the bits of a hash so that similar ids get unrelated samples. FNV-1a
+/// barely changes the high bits for ids that only differ in their last bytes.
+fn mix(mut hash: u64) -> u64 {
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_rates() {
+        let rates = SampleRates::default();
+        assert!(rates.is_all());
+        assert_eq!(rates.rate(None), 1.0);
+        assert_eq!(rates.rate(Some("event")), 1.0);
+        assert!(rates.keep(Some("event"), 0.999));
+        assert_eq!(rates, SampleRates::new(None, None));
+    }
+
+    #[test]
+    fn item_rates() {
+        let rates = SampleRates::new(
+            Some(0.5),
+            Some(BTreeMap::from([
+                ("replay_recording".to_string(), 0.0),
+                ("event".to_string(), 1.0),
+            ])),
+        );
+        assert!(!rates.is_all());
+        assert!(rates.has_item_rates());
+        assert!(!SampleRates::new(Some(0.5), None).has_item_rates());
+        assert_eq!(rates.rate(None), 0.5);
+        assert_eq!(rates.rate(Some("transaction")), 0.5);
+        assert!(rates.keep(Some("event"), 0.9));
+        assert!(!rates.keep(Some("transaction"), 0.9));
+        assert!(rates.keep(Some("transaction"), 0.1));
+        assert!(!rates.keep(Some("replay_recording"), 0.0));
+    }
+
+    #[test]
+    fn invalid_rates() {
+        assert_eq!(SampleRates::new(Some(0.0), None).invalid_rate(), None);
+        assert_eq!(SampleRates::new(Some(1.5), None).invalid_rate(), Some(1.5));
+        let rates = SampleRates::new(None, Some(BTreeMap::from([("event".to_string(), -0.1)])));
+        assert_eq!(rates.invalid_rate(), Some(-0.1));
+        assert!(SampleRates::new(Some(f64::NAN), None)
+            .invalid_rate()
+            .is_some());
+    }
+
+    #[test]
+    fn deterministic_samples() {
+        let trace_id = "4c79f60c11214eb38604f4ae0781bfb2";
+        assert_eq!(sample(Some(trace_id)), sample(Some(trace_id)));
+        assert_ne!(
+            sample(Some(trace_id)),
+            sample(Some("9ec79c33ec9942ab8353589fcb2e04dc"))
+        );
+        assert_eq!(fnv1a(b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(b"a"), 0xaf63dc4c8601ec8c);
+    }
+
+    #[test]
+    fn samples_are_uniform() {
+        // About 10% of ids should be kept at a 10% sample rate
+        let rates = SampleRates::new(Some(0.1), None);
+        let kept = (0..10_000)
+            .map(|i| format!("{i:032x}"))
+            .filter(|id| rates.keep(None, sample(Some(id))))
+            .count();
+        assert!((800..1200).contains(&kept), "kept {kept}");
+        for i in 0..1000 {
+            let value = sample(Some(&i.to_string()));
+            assert!((0.0..1.0).contains(&value));
+        }
+    }
+}
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// A change in whether or not an outbound DSN is active.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transition {
+    Activated,
+    Expired,
+}
+
+impl Transition {
+    /// The label used for metrics.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Transition::Activated => "activated",
+            Transition::Expired => "expired",
+        }
+    }
+}
+
+/// The time window an outbound DSN receives requests in.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    /// Requests are sent from this time on. Always active when unset.
+    from: Option<SystemTime>,
+    /// Requests are sent until this time. Never expires when unset.
+    until: Option<SystemTime>,
+    /// Whether or not the DSN was active when it was last checked, shared by
+    /// clones of the schedule so that each transition is reported once.
+    active: Arc<AtomicBool>,
+}
+
+impl Default for Schedule {
+    fn default() -> Self {
+        Schedule {
+            from: None,
+            until: None,
+            active: Arc::new(AtomicBool::new(true)),
+        }
+    }
+}
+
+impl PartialEq for Schedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.from == other.from && self.until == other.until
+    }
+}
+
+impl Schedule {
+    /// Create a schedule from RFC 3339 timestamps. Returns the name of the
+    /// field that could not be parsed on failure.
+    pub fn new(
+        active_from: Option<&str>,
+        active_until: Option<&str>,
+    ) -> Result<Schedule, &'static str> {
+        let from = active_from
+            .map(|value| parse_timestamp(value).ok_or("active_from"))
+            .transpose()?;
+        let until = active_until
+            .map(|value| parse_timestamp(value).ok_or("active_until"))
+            .transpose()?;
+        let schedule = Schedule {
+            from,
+            until,
+            active: Arc::new(AtomicBool::new(false)),
+        };
+        schedule
+            .active
+            .store(schedule.is_active(SystemTime::now()), Ordering::Relaxed);
+
+        Ok(schedule)
+    }
+
+    /// Whether or not `active_until` is before `active_from`, and the DSN would never be active.
+    pub fn is_empty(&self) -> bool {
+        matches!((self.from, self.until), (Some(from), Some(until)) if until <= from)
+    }
+
+    pub fn is_active(&self, now: SystemTime) -> bool {
+        self.from.map_or(true, |from| from <= now) && self.until.map_or(true, |until| now < until)
+    }
+
+    /// Check whether or not the DSN is active at `now`, and whether that changed
+    /// since the last check.
+    pub fn check(&self, now: SystemTime) -> (bool, Option<Transition>) {
+        let active = self.is_active(now);
+        if self.active.swap(active, Ordering::Relaxed) == active {
+            return (active, None);
+        }
+        let transition = if active {
+            Transition::Activated
+        } else {
+            Transition::Expired
+        };
+        (active, Some(transition))
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let format = |time: Option<SystemTime>| match time {
+            Some(time) => OffsetDateTime::from(time)
+                .format(&Rfc3339)
+                .unwrap_or_default(),
+            None => "-".to_string(),
+        };
+        write!(f, "{0} to {1}", format(self.from), format(self.until))
+    }
+}
+
+fn parse_timestamp(value: &str) -> Option<SystemTime> {
+    OffsetDateTime::parse(value, &Rfc3339)
+        .ok()
+        .map(SystemTime::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn parse_timestamps() {
+        let schedule = Schedule::new(
+            Some("2024-06-01T00:00:00Z"),
+            Some("2024-06-01T02:00:00+02:00"),
+        )
+        .unwrap();
+        assert_eq!(schedule.from, Some(at(1717200000)));
+        assert_eq!(schedule.until, Some(at(1717200000)));
+        assert!(schedule.is_empty());
+        assert_eq!(
+            schedule.to_string(),
+            "2024-06-01T00:00:00Z to 2024-06-01T00:00:00Z"
+        );
+
+        assert_eq!(
+            Schedule::new(Some("2024-06-01"), None).unwrap_err(),
+            "active_from"
+        );
+        assert_eq!(
+            Schedule::new(None, Some("tomorrow")).unwrap_err(),
+            "active_until"
+        );
+        assert_eq!(Schedule::new(None, None).unwrap(), Schedule::default());
+    }
+
+    #[test]
+    fn active_window() {
+        let schedule =
+            Schedule::new(Some("2024-06-01T00:00:00Z"), Some("2024-06-02T00:00:00Z")).unwrap();
+        assert!(!schedule.is_empty());
+        assert!(!schedule.is_active(at(1717200000 - 1)));
+        assert!(schedule.is_active(at(1717200000)));
+        assert!(schedule.is_active(at(1717286400 - 1)));
+        assert!(!schedule.is_active(at(1717286400)));
+
+        let schedule = Schedule::default();
+        assert!(schedule.is_active(at(0)));
+        assert!(schedule.is_active(SystemTime::now()));
+    }
+
+    #[test]
+    fn report_transitions_once() {
+        let schedule =
+            Schedule::new(Some("2024-06-01T00:00:00Z"), Some("2024-06-02T00:00:00Z")).unwrap();
+        // The schedule has expired when it is created
+        assert_eq!(schedule.check(at(1717286400)), (false, None));
+
+        let clone = schedule.clone();
+        assert_eq!(
+            schedule.check(at(1717200000)),
+            (true, Some(Transition::Activated))
+        );
+        assert_eq!(clone.check(at(1717200001)), (true, None));
+        assert_eq!(
+            clone.check(at(1717286400)),
+            (false, Some(Transition::Expired))
+        );
+        assert_eq!(schedule.check(at(1717286401)), (false, None));
+    }
+}
@@ -1,44 +1,157 @@
 use futures::future::join_all;
-use hyper_util::client::legacy::{Client, ResponseFuture};
-use hyper_util::rt::TokioExecutor;
+use futures::stream::{FuturesUnordered, StreamExt};
 use log::{debug, info, warn};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, sync::Arc, time::SystemTime};
 
 use http_body_util::{BodyExt, Full};
-use hyper::body::{Bytes, Incoming};
-use hyper::{Method, StatusCode};
+use hyper::body::{Body, Bytes};
+use hyper::header::{HeaderValue, CONTENT_ENCODING, ORIGIN};
+use hyper::{HeaderMap, Method, StatusCode, Uri};
 use hyper::{Request, Response};
-use hyper_tls::HttpsConnector;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 
+use crate::client::{ClientError, OutboundClient};
+use crate::config::SpoolConfig;
+use crate::cors;
 use crate::dsn;
+use crate::envelope::{Envelope, EnvelopeHeader};
+use crate::health;
+use crate::metrics::Metrics;
 use crate::request;
+use crate::retry;
+use crate::route::{Endpoint, Route, UnknownRoute};
+use crate::sampling;
+use crate::spool::Spool;
 
 type GenericError = Box<dyn std::error::Error + Send + Sync>;
 type Result<T> = std::result::Result<T, GenericError>;
 type BoxBody = http_body_util::combinators::BoxBody<Bytes, hyper::Error>;
 
-pub async fn handle_request(
-    req: Request<Incoming>,
+/// Headers from the primary outbound response that are returned to the client.
+const PROXY_RESPONSE_HEADERS: [&str; 4] = [
+    "content-type",
+    "retry-after",
+    "x-sentry-error",
+    "x-sentry-rate-limits",
+];
+
+/// The longest envelope header that is read without decompressing the whole body.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Shared state used to deliver outbound requests.
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// Pooled client shared by all outbound requests.
+    pub client: OutboundClient,
+    /// Prometheus metrics served on the admin port.
+    pub metrics: Arc<Metrics>,
+    /// Spool for requests that could not be delivered.
+    pub spool: Option<Arc<Spool>>,
+    /// Outbound deliveries run as tracked tasks so that they can be drained on shutdown.
+    pub tasks: TaskTracker,
+    /// Cancelled when the shutdown deadline passes. Deliveries that are still running
+    /// are abandoned, and spooled when their keyring has spooling enabled.
+    pub abandon: CancellationToken,
+    /// Respond as soon as an outbound DSN responds successfully instead of waiting
+    /// for all outbound requests to complete.
+    pub answer_early: bool,
+    /// Checks outbound hosts in readiness checks. Outbound hosts are not checked when unset.
+    pub upstream_checks: Option<Arc<health::UpstreamChecker>>,
+}
+
+pub async fn handle_request<B>(
+    req: Request<B>,
     keymap: Arc<HashMap<String, dsn::DsnKeyRing>>,
-) -> Result<Response<BoxBody>> {
-    let method = req.method();
+    context: Context,
+) -> Result<Response<BoxBody>>
+where
+    B: Body<Data = Bytes>,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    // Browsers only send requests from origins allowed by the keyring, and
+    // every response gets CORS headers so that SDKs can read errors.
+    let route = Route::parse(req.uri().path());
+    let origin = req.headers().get(ORIGIN).cloned();
+    let allowed_origins = find_public_key(route.as_ref().ok(), req.uri(), req.headers())
+        .and_then(|public_key| keymap.get(&public_key))
+        .and_then(|keyring| keyring.allowed_origins.as_deref());
+    let allow_origin = cors::allow_origin(origin.as_ref(), allowed_origins);
+    let mut response = if allow_origin.is_none() {
+        debug!("Rejecting request from origin {0:?}", origin);
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(full("Origin not allowed"))
+            .unwrap()
+    } else if req.method() == Method::OPTIONS {
+        let mut res = Response::new(full(Bytes::new()));
+        cors::add_preflight_headers(res.headers_mut());
+        res
+    } else {
+        match forward_request(req, route, keymap, context).await {
+            Ok(res) => res,
+            Err(e) => {
+                warn!("Could not read request: {0}", e);
+                Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(full("Could not read request"))
+                    .unwrap()
+            }
+        }
+    };
+    cors::add_headers(response.headers_mut(), allow_origin);
+
+    Ok(response)
+}
+
+/// Get the public key of a request from its path, or its headers and query string.
+fn find_public_key(route: Option<&Route>, uri: &Uri, headers: &HeaderMap) -> Option<String> {
+    match route.and_then(Route::public_key) {
+        Some(public_key) => Some(public_key.to_string()),
+        None => dsn::from_request(uri, headers),
+    }
+}
+
+async fn forward_request<B>(
+    req: Request<B>,
+    route: std::result::Result<Route, UnknownRoute>,
+    keymap: Arc<HashMap<String, dsn::DsnKeyRing>>,
+    context: Context,
+) -> Result<Response<BoxBody>>
+where
+    B: Body<Data = Bytes>,
+    B::Error: std::error::Error + Send + Sync + 'static,
+{
+    let method = req.method().clone();
     let uri = req.uri().clone();
     let path = uri.path();
     let headers = req.headers().clone();
     let user_agent = match headers.get("user-agent") {
-        Some(header) => {
-            if let Ok(v) = header.to_str() {
-                v
-            } else {
-                "no-agent"
-            }
-        }
+        Some(header) => header.to_str().unwrap_or("no-agent"),
         None => "no-agent",
     };
     info!("{method} {path} {user_agent}");
 
-    // All store/envelope requests are POST
-    if method != Method::POST {
+    // Health checks are answered before looking for a DSN
+    if method == Method::GET || method == Method::HEAD {
+        if path == health::HEALTH_PATH {
+            return Ok(json_response(StatusCode::OK, &health::liveness()));
+        }
+        if path == health::READY_PATH {
+            let readiness = health::readiness(&keymap, context.upstream_checks.as_deref()).await;
+            if readiness.status != "ready" {
+                warn!("Readiness check is {0}", readiness.status);
+            }
+            return Ok(json_response(readiness.status_code(), &readiness));
+        }
+    }
+    // Ingest requests are POST, apart from cron check-ins that can also be
+    // sent with GET or HEAD
+    let check_in = matches!(&route, Ok(route) if route.endpoint == Endpoint::Cron)
+        && (method == Method::GET || method == Method::HEAD);
+    if method != Method::POST && !check_in {
         debug!("Received a non POST request");
         let res = Response::builder()
             .status(StatusCode::METHOD_NOT_ALLOWED)
@@ -46,8 +159,21 @@ pub async fn handle_request(
             .unwrap();
         return Ok(res);
     }
-    // Find DSN public key in request
-    let found_dsn = dsn::from_request(&uri, &headers);
+    // Only ingest endpoints are mirrored
+    let route = match route {
+        Ok(route) => route,
+        Err(e) => {
+            debug!("Rejecting request: {0}", e);
+            context.metrics.unknown_endpoint.inc();
+            let res = Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(full(e.to_string()))
+                .unwrap();
+            return Ok(res);
+        }
+    };
+    // Find DSN public key in the request path, or the headers and query string
+    let found_dsn = find_public_key(Some(&route), &uri, &headers);
     if found_dsn.is_none() {
         debug!("Could not find a DSN in the request headers or URI");
         return Ok(bad_request_response());
@@ -59,73 +185,309 @@ pub async fn handle_request(
         // If a DSN cannot be found -> empty response
         None => {
             debug!("Could not find a match DSN in the configured keys");
+            context.metrics.unknown_dsn.inc();
             return Ok(bad_request_response());
         }
     };
-    let mut body_bytes = req.collect().await?.to_bytes();
+    context
+        .metrics
+        .inbound_requests
+        .with_label_values(&[&public_key, route.endpoint.as_str()])
+        .inc();
+    let received_body = req.collect().await?.to_bytes();
+    let mut body_bytes = received_body.clone();
+    let store = route.endpoint == Endpoint::Store;
 
-    // Bodies can be compressed
-    if headers.contains_key("content-encoding") {
-        let request_encoding = headers.get("content-encoding").unwrap();
+    // Bodies can be compressed. When no outbound DSN routes items, only the header
+    // of envelopes could change, and envelopes without a DSN in it are forwarded as
+    // they were received, without keeping the decompressed body in memory.
+    let received_encoding = headers.get(CONTENT_ENCODING);
+    let unchanged_header = match received_encoding {
+        Some(encoding)
+            if route.endpoint == Endpoint::Envelope
+                && !keyring.outbound.iter().any(routes_items) =>
+        {
+            match read_unchanged_header(encoding, &received_body) {
+                Ok(header) => header,
+                Err(e) => return Ok(decode_failure(&context.metrics, e)),
+            }
+        }
+        _ => None,
+    };
+    if unchanged_header.is_some() {
+        context.metrics.decode_skipped.inc();
+    } else if let Some(request_encoding) = received_encoding {
         body_bytes = match request::decode_body(request_encoding, &body_bytes) {
             Ok(decompressed) => decompressed,
+            Err(e) => return Ok(decode_failure(&context.metrics, e)),
+        }
+    }
+
+    // Envelopes are parsed once and re-serialized for each outbound DSN. Bodies that
+    // can't be parsed fall back to replacing the DSN in the envelope header only.
+    // Store events are read as an envelope with one item so that they are routed
+    // and sampled like envelopes.
+    let envelope = if unchanged_header.is_some() {
+        None
+    } else if route.endpoint == Endpoint::Envelope {
+        match Envelope::parse(&body_bytes) {
+            Ok(envelope) => Some(envelope),
             Err(e) => {
-                warn!("Could not decode request body: {0:?}", e);
-                return Ok(bad_request_response());
+                debug!("Could not parse envelope: {0}", e);
+                None
             }
         }
-    }
+    } else if store {
+        Envelope::from_store(&body_bytes)
+    } else {
+        None
+    };
+
+    // Requests are sampled by their trace or event id, so that every outbound DSN
+    // with the same sample rate keeps the same events and traces.
+    let sample_id = match (&envelope, &unchanged_header) {
+        (Some(envelope), _) => envelope.sample_id(),
+        (None, Some(header)) => header.sample_id(),
+        _ => None,
+    };
+    let sample = sampling::sample(sample_id);
 
-    // We'll race requests to the outbound DSN's and once all requests are complete
-    // we use the body of the first response
+    // Outbound requests are sent in tracked tasks so that the remaining deliveries
+    // can complete after we respond. When a keyring has a primary DSN, the primary's
+    // first response is returned, and failed requests are retried in the background.
+    // Otherwise, or when the primary DSN doesn't receive any items,
+    // we'll race requests to the outbound DSN's and use the body of the first response.
+    let mut primary = None;
     let mut responses = Vec::new();
+    let now = SystemTime::now();
     for outbound_dsn in keyring.outbound.iter() {
-        debug!("Creating outbound request for {0}", &outbound_dsn.host);
-        let request_builder = request::make_outbound_request(&uri, &headers, outbound_dsn);
-        let body_out = match request::replace_envelope_dsn(&body_bytes, outbound_dsn) {
-            Some(new_body) => new_body,
-            None => body_bytes.clone(),
+        // Outbound DSNs outside of their active window are skipped
+        let (active, transition) = outbound_dsn.schedule.check(now);
+        if let Some(transition) = transition {
+            info!(
+                "Outbound DSN {0} for {1} {2}, active {3}",
+                &outbound_dsn.dsn.host,
+                &public_key,
+                transition.as_str(),
+                &outbound_dsn.schedule
+            );
+            context
+                .metrics
+                .schedule_transitions
+                .with_label_values(&[&outbound_dsn.dsn.host, transition.as_str()])
+                .inc();
+        }
+        if !active {
+            skip_outbound(&context.metrics, outbound_dsn, "inactive");
+            continue;
+        }
+        debug!("Creating outbound request for {0}", &outbound_dsn.dsn.host);
+        let mut request_builder = request::make_outbound_request(&uri, &headers, &outbound_dsn.dsn);
+        if check_in {
+            request_builder = request_builder.method(method.clone());
+        }
+        let body_out = match &envelope {
+            Some(envelope) => {
+                let mut envelope = envelope.clone();
+                envelope.replace_dsn(&outbound_dsn.dsn);
+                if let Some(result) = route_items(&mut envelope, outbound_dsn, sample) {
+                    skip_outbound(&context.metrics, outbound_dsn, result);
+                    continue;
+                }
+                for item in envelope.items() {
+                    debug!(
+                        "Forwarding {0} item ({1} bytes) to {2}",
+                        item.item_type(),
+                        item.payload().len(),
+                        &outbound_dsn.dsn.host
+                    );
+                }
+                if store {
+                    let event = request::replace_store_project(&body_bytes, &outbound_dsn.dsn)
+                        .unwrap_or_else(|| body_bytes.clone());
+                    match Envelope::from_store(&event) {
+                        Some(converted) if outbound_dsn.store_as_envelope => {
+                            request_builder = request::store_to_envelope_request(request_builder);
+                            converted.to_bytes()
+                        }
+                        _ => event,
+                    }
+                } else {
+                    envelope.to_bytes()
+                }
+            }
+            None => {
+                // Items can't be routed in envelopes that couldn't be parsed, and the
+                // envelope is not sent rather than sending items the DSN doesn't want.
+                if route.endpoint == Endpoint::Envelope && routes_items(outbound_dsn) {
+                    skip_outbound(&context.metrics, outbound_dsn, "unparsed");
+                    continue;
+                }
+                if !outbound_dsn.sample_rates.keep(None, sample) {
+                    skip_outbound(&context.metrics, outbound_dsn, "sampled");
+                    continue;
+                }
+                if unchanged_header.is_some() {
+                    body_bytes.clone()
+                } else {
+                    match request::replace_envelope_dsn(&body_bytes, &outbound_dsn.dsn) {
+                        Some(new_body) => new_body,
+                        None => body_bytes.clone(),
+                    }
+                }
+            }
+        };
+        // Bodies that weren't rewritten are forwarded as they were received. Other
+        // bodies are compressed with the outbound DSN's codec.
+        let body_out = match received_encoding {
+            Some(encoding) if body_out == body_bytes => {
+                request_builder = request_builder.header(CONTENT_ENCODING, encoding);
+                received_body.clone()
+            }
+            _ => match outbound_dsn.compression.content_encoding() {
+                Some(encoding) if !body_out.is_empty() => {
+                    match outbound_dsn.compression.compress(&body_out) {
+                        Ok(compressed) => {
+                            request_builder = request_builder.header(CONTENT_ENCODING, encoding);
+                            compressed
+                        }
+                        Err(e) => {
+                            warn!("Could not compress request body: {0}", e);
+                            body_out
+                        }
+                    }
+                }
+                _ => body_out,
+            },
         };
         let request = request_builder.body(Full::new(body_out));
 
         if let Ok(outbound_request) = request {
-            let fut_res = send_request(outbound_request);
-            responses.push(fut_res);
+            let first_response = if outbound_dsn.primary {
+                let (sender, receiver) = oneshot::channel();
+                primary = Some(receiver);
+                Some(sender)
+            } else {
+                None
+            };
+            let handle = context.tasks.spawn(deliver(
+                context.clone(),
+                outbound_request,
+                outbound_dsn.clone(),
+                keyring.spool.clone(),
+                first_response,
+            ));
+            if !outbound_dsn.primary {
+                responses.push(handle);
+            }
         } else {
             warn!("Could not build request {0:?}", request.err());
         }
     }
 
-    let mut found_body = false;
-    let mut resp_body = Bytes::new();
-    // Wait for responses to finish and use the first one's body
-    for fut_res in join_all(responses).await {
-        let response_res = fut_res.await;
-        if found_body {
-            continue;
-        }
-        if let Ok(response) = response_res {
-            if let Ok(response_body) = response.collect().await {
-                resp_body = response_body.to_bytes();
-                found_body = true;
+    let mut response_builder = Response::builder();
+
+    if let Some(primary) = primary {
+        let response = match primary.await {
+            Ok(response) => response,
+            Err(_) => {
+                let res = response_builder
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(full("Could not reach upstream"))
+                    .unwrap();
+                return Ok(res);
+            }
+        };
+        // Pass the status and rate limits along so that SDKs can back off
+        response_builder = response_builder.status(response.status());
+        for name in PROXY_RESPONSE_HEADERS {
+            for value in response.headers().get_all(name) {
+                response_builder = response_builder.header(name, value);
             }
-        } else {
-            warn!("Could not make request: {0:?}", response_res.err());
         }
+        return Ok(response_builder.body(full(response.into_body())).unwrap());
     }
 
-    // Add cors headers necessary for browser events
-    let response_builder = Response::builder()
-        .header("Access-Control-Allow-Origin", "*")
-        .header(
-            "Access-Control-Expose-Headers",
-            "x-sentry-error,x-sentry-rate-limit,retry-after",
-        )
-        .header("Cross-Origin-Resource-Policy", "cross-origin");
+    let resp_body = if context.answer_early {
+        first_success_body(responses).await
+    } else {
+        first_body(responses).await
+    };
 
     Ok(response_builder.body(full(resp_body)).unwrap())
 }
 
+type Delivery = JoinHandle<Result<Response<Bytes>>>;
+
+/// Whether or not an outbound DSN drops items or samples them by type, which
+/// requires the whole envelope.
+fn routes_items(outbound: &dsn::Outbound) -> bool {
+    !outbound.items.is_all() || outbound.sample_rates.has_item_rates()
+}
+
+/// Read the header of a compressed envelope. Returns the header when the envelope can
+/// be forwarded unchanged, because the header has no DSN or public key to replace.
+/// Headers that aren't JSON objects are never replaced, and are returned as an empty header.
+fn read_unchanged_header(
+    encoding: &HeaderValue,
+    body: &Bytes,
+) -> std::result::Result<Option<EnvelopeHeader>, request::BodyError> {
+    let line = match request::decode_first_line(encoding, body, MAX_HEADER_SIZE)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let header = EnvelopeHeader::parse(line).unwrap_or_default();
+    if header.has_dsn() {
+        return Ok(None);
+    }
+
+    // The rest of the envelope is forwarded without decoding it, and upstreams
+    // reject bodies that are corrupt or truncated
+    Ok(Some(header))
+}
+
+/// Count a request body that could not be decoded, and get the response for it.
+fn decode_failure(metrics: &Metrics, e: request::BodyError) -> Response<BoxBody> {
+    warn!("Could not decode request body: {0}", e);
+    let reason = match e {
+        request::BodyError::UnsupportedCodec(_) => "unsupported_codec",
+        request::BodyError::CouldNotDecode(_) => "invalid_body",
+        request::BodyError::InvalidHeader => "invalid_header",
+    };
+    metrics.decode_failures.with_label_values(&[reason]).inc();
+
+    bad_request_response()
+}
+
+/// Wait for all deliveries to finish and get the body of the first response
+async fn first_body(responses: Vec<Delivery>) -> Bytes {
+    for response_res in join_all(responses).await {
+        if let Ok(Ok(response)) = response_res {
+            return response.into_body();
+        }
+    }
+    Bytes::new()
+}
+
+/// Get the body of the first successful response without waiting for the
+/// remaining deliveries. If no delivery succeeds, the first response received is used.
+async fn first_success_body(responses: Vec<Delivery>) -> Bytes {
+    let mut pending: FuturesUnordered<Delivery> = responses.into_iter().collect();
+    let mut fallback = None;
+    while let Some(response_res) = pending.next().await {
+        let response = match response_res {
+            Ok(Ok(response)) => response,
+            _ => continue,
+        };
+        if response.status().is_success() {
+            return response.into_body();
+        } else if fallback.is_none() {
+            fallback = Some(response.into_body());
+        }
+    }
+    fallback.unwrap_or_default()
+}
+
 fn bad_request_response() -> Response<BoxBody> {
     Response::builder()
         .status(StatusCode::BAD_REQUEST)
@@ -133,16 +495,512 @@ fn bad_request_response() -> Response<BoxBody> {
         .unwrap()
 }
 
+fn json_response<T: serde::Serialize>(status: StatusCode, value: &T) -> Response<BoxBody> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(full(serde_json::to_vec(value).unwrap_or_default()))
+        .unwrap()
+}
+
 fn full<T: Into<Bytes>>(chunk: T) -> BoxBody {
     Full::new(chunk.into())
         .map_err(|never| match never {})
         .boxed()
 }
 
-/// Send a request to its destination async
-async fn send_request(req: Request<Full<Bytes>>) -> ResponseFuture {
-    let https = HttpsConnector::new();
-    let client = Client::builder(TokioExecutor::new()).build::<_, Full<Bytes>>(https);
+/// Remove the envelope items that aren't routed to `outbound`, or not sampled for it.
+/// Returns the reason the request is not sent when no items remain.
+fn route_items(
+    envelope: &mut Envelope,
+    outbound: &dsn::Outbound,
+    sample: f64,
+) -> Option<&'static str> {
+    // Only the item types configured for the outbound DSN are forwarded
+    if !outbound.items.is_all() {
+        envelope.retain_items(|item| outbound.items.allows(item.item_type()));
+        if envelope.items().is_empty() {
+            return Some("filtered");
+        }
+    }
+    if !outbound.sample_rates.is_all() {
+        envelope.retain_items(|item| outbound.sample_rates.keep(Some(item.item_type()), sample));
+        if envelope.items().is_empty() {
+            return Some("sampled");
+        }
+    }
+    None
+}
+
+/// Record an outbound DSN that the request is not sent to. `result` is `inactive`
+/// outside of the DSN's schedule, `filtered` when no envelope items are routed to
+/// the DSN, `unparsed` when items can't be routed because the envelope couldn't be
+/// parsed, or `sampled`.
+fn skip_outbound(metrics: &Metrics, outbound: &dsn::Outbound, result: &str) {
+    debug!(
+        "Request was {0} for {1}, skipping",
+        result, &outbound.dsn.host
+    );
+    metrics
+        .outbound_deliveries
+        .with_label_values(&[&outbound.dsn.host, result])
+        .inc();
+}
+
+/// Send a request to an outbound DSN, retrying failures with the DSN's retry policy.
+/// Requests that still fail are written to the spool when the keyring has spooling enabled.
+/// A copy of the first response is sent to `first_response`, before any retries.
+async fn deliver(
+    context: Context,
+    req: Request<Full<Bytes>>,
+    outbound: dsn::Outbound,
+    settings: Option<SpoolConfig>,
+    mut first_response: Option<oneshot::Sender<Response<Bytes>>>,
+) -> Result<Response<Bytes>> {
+    let response_res = tokio::select! {
+        response_res = send_with_retries(&context.client, &req, &outbound, &mut first_response) => response_res,
+        _ = context.abandon.cancelled() => {
+            warn!("Abandoned request to {0} at shutdown", outbound.dsn.host);
+            context
+                .metrics
+                .outbound_deliveries
+                .with_label_values(&[&outbound.dsn.host, "abandoned"])
+                .inc();
+            if let (Some(settings), Some(spool)) = (settings, context.spool) {
+                match spool.push(&outbound.dsn, &settings, req).await {
+                    Ok(_) => info!("Spooled abandoned request for {0}", outbound.dsn.host),
+                    Err(e) => warn!("Could not spool request for {0}: {1}", outbound.dsn.host, e),
+                }
+            }
+            return Err("request abandoned at shutdown".into());
+        }
+    };
+    let failed = match &response_res {
+        Ok(response) => retry::is_retryable(response.status()),
+        Err(_) => true,
+    };
+    let result = match &response_res {
+        _ if failed => "failed",
+        Ok(response) if response.status().is_success() => "success",
+        Ok(_) => "rejected",
+        Err(_) => "failed",
+    };
+    context
+        .metrics
+        .outbound_deliveries
+        .with_label_values(&[&outbound.dsn.host, result])
+        .inc();
+    if failed {
+        if let (Some(settings), Some(spool)) = (settings, context.spool) {
+            match spool.push(&outbound.dsn, &settings, req).await {
+                Ok(_) => info!("Spooled failed request for {0}", outbound.dsn.host),
+                Err(e) => warn!("Could not spool request for {0}: {1}", outbound.dsn.host, e),
+            }
+        }
+    }
+
+    if let Err(e) = &response_res {
+        warn!("Could not make request to {0}: {1:?}", outbound.dsn.host, e);
+    }
+
+    Ok(response_res?)
+}
+
+/// Copy the status, headers and body of a response.
+fn copy_response(response: &Response<Bytes>) -> Response<Bytes> {
+    let mut copy = Response::new(response.body().clone());
+    *copy.status_mut() = response.status();
+    *copy.headers_mut() = response.headers().clone();
+    copy
+}
+
+/// Send a request, retrying failures with the outbound DSN's retry policy.
+async fn send_with_retries(
+    client: &OutboundClient,
+    req: &Request<Full<Bytes>>,
+    outbound: &dsn::Outbound,
+    first_response: &mut Option<oneshot::Sender<Response<Bytes>>>,
+) -> std::result::Result<Response<Bytes>, ClientError> {
+    let mut attempt = 1;
+    loop {
+        let response_res = client.send(req.clone()).await;
+        // Dropping the sender after a failed attempt answers the client with a 502
+        if let Some(sender) = first_response.take() {
+            if let Ok(response) = &response_res {
+                sender.send(copy_response(response)).ok();
+            }
+        }
+        let delay = match &response_res {
+            Ok(response) if !retry::is_retryable(response.status()) => return response_res,
+            Ok(response) => outbound.retry.next_delay(
+                attempt,
+                Some(response.status()),
+                Some(response.headers()),
+            ),
+            Err(_) => outbound.retry.next_delay(attempt, None, None),
+        };
+        match delay {
+            Some(delay) => {
+                debug!(
+                    "Retrying request to {0} in {1}ms",
+                    outbound.dsn.host,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            None => return response_res,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ClientConfig, KeyRing};
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper_util::rt::TokioIo;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    const AUTH: &str = "Sentry sentry_key=aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+
+    /// The path, headers and body of a request received by a test upstream.
+    type Received = (Uri, HeaderMap, Bytes);
+
+    /// Start an upstream that records the requests it receives, and responds with `status`.
+    async fn upstream(status: StatusCode) -> (String, mpsc::UnboundedReceiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let sender = sender.clone();
+                    async move {
+                        let (parts, body) = req.into_parts();
+                        let body = body.collect().await?.to_bytes();
+                        sender.send((parts.uri, parts.headers, body)).ok();
+                        Ok::<_, GenericError>(
+                            Response::builder().status(status).body(full("{}")).unwrap(),
+                        )
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (addr, receiver)
+    }
+
+    /// Load keys from YAML, with every outbound DSN sending requests to `upstream`.
+    fn keymap(yaml: &str, upstream: &str) -> Arc<HashMap<String, dsn::DsnKeyRing>> {
+        let keys: Vec<KeyRing> = serde_yaml::from_str(yaml).unwrap();
+        let mut keymap = dsn::make_key_map(keys, None).unwrap();
+        for keyring in keymap.values_mut() {
+            for outbound in keyring.outbound.iter_mut() {
+                outbound.dsn.host = upstream.to_string();
+            }
+        }
+        Arc::new(keymap)
+    }
+
+    fn context() -> Context {
+        let metrics = Arc::new(Metrics::new());
+        Context {
+            client: OutboundClient::new(&ClientConfig::default(), metrics.clone()).unwrap(),
+            metrics,
+            spool: None,
+            tasks: TaskTracker::new(),
+            abandon: CancellationToken::new(),
+            answer_early: false,
+            upstream_checks: None,
+        }
+    }
+
+    fn envelope_request() -> hyper::http::request::Builder {
+        Request::builder()
+            .method(Method::POST)
+            .uri("http://localhost/api/1/envelope/")
+            .header("x-sentry-auth", AUTH)
+    }
+
+    async fn forward(
+        req: Request<Full<Bytes>>,
+        keymap: &Arc<HashMap<String, dsn::DsnKeyRing>>,
+        context: &Context,
+    ) -> Response<BoxBody> {
+        let route = Route::parse(req.uri().path());
+        forward_request(req, route, keymap.clone(), context.clone())
+            .await
+            .unwrap()
+    }
 
-    client.request(req)
+    fn received(receiver: &mut mpsc::UnboundedReceiver<Received>) -> Vec<Received> {
+        let mut requests = Vec::new();
+        while let Ok(request) = receiver.try_recv() {
+            requests.push(request);
+        }
+        requests
+    }
+
+    #[tokio::test]
+    async fn unparsed_envelopes_skip_item_filters() {
+        let (addr, mut receiver) = upstream(StatusCode::OK).await;
+        let keymap = keymap(
+            r#"
+- inbound: http://aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa@localhost/1
+  outbound:
+    - http://bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb@sentry.io/2
+    - dsn: http://cccccccccccccccccccccccccccccccc@sentry.io/3
+      exclude_items: [replay_recording]
+"#,
+            &addr,
+        );
+        let context = context();
+        // The replay recording is shorter than its length
+        let body = b"{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}\n{\"type\":\"replay_recording\",\"length\":1000}\n{}";
+        let req = envelope_request()
+            .body(Full::new(Bytes::from_static(body)))
+            .unwrap();
+        let response = forward(req, &keymap, &context).await;
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let requests = received(&mut receiver);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].0.path(), "/api/2/envelope/");
+        assert_eq!(requests[0].2, Bytes::from_static(body));
+        let unparsed = context
+            .metrics
+            .outbound_deliveries
+            .with_label_values(&[&addr, "unparsed"])
+            .get();
+        assert_eq!(unparsed, 1);
+    }
+
+    fn gzip(body: &[u8]) -> Bytes {
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(body).unwrap();
+        Bytes::from(encoder.finish().unwrap())
+    }
+
+    const FORWARD_KEYS: &str = r#"
+- inbound: http://aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa@localhost/1
+  outbound:
+    - http://bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb@sentry.io/2
+"#;
+
+    #[tokio::test]
+    async fn forward_compressed_envelopes_unchanged() {
+        let (addr, mut receiver) = upstream(StatusCode::OK).await;
+        let keymap = keymap(FORWARD_KEYS, &addr);
+        let context = context();
+
+        let body = gzip(
+            b"{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}\n{\"type\":\"event\"}\n{}\n",
+        );
+        let req = envelope_request()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Full::new(body.clone()))
+            .unwrap();
+        let response = forward(req, &keymap, &context).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let requests = received(&mut receiver);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].1[CONTENT_ENCODING], "gzip");
+        assert_eq!(requests[0].2, body);
+        assert_eq!(context.metrics.decode_skipped.get(), 1);
+
+        // Envelopes with a DSN are decompressed and rewritten
+        let req = envelope_request()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Full::new(gzip(
+                b"{\"dsn\":\"http://aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa@localhost/1\"}\n{\"type\":\"event\"}\n{}\n",
+            )))
+            .unwrap();
+        forward(req, &keymap, &context).await;
+        let requests = received(&mut receiver);
+        assert!(!requests[0].1.contains_key(CONTENT_ENCODING));
+        assert!(requests[0]
+            .2
+            .starts_with(b"{\"dsn\":\"http://bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb@"));
+        assert_eq!(context.metrics.decode_skipped.get(), 1);
+
+        // Only envelopes take the fast path, other bodies are decompressed
+        let body = gzip(b"{\"csp-report\":{}}");
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("http://localhost/api/1/security/?sentry_key=aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Full::new(body.clone()))
+            .unwrap();
+        forward(req, &keymap, &context).await;
+        let requests = received(&mut receiver);
+        assert_eq!(requests[0].2, body);
+        assert_eq!(context.metrics.decode_skipped.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn forward_truncated_compressed_envelopes() {
+        let (addr, mut receiver) = upstream(StatusCode::OK).await;
+        let keymap = keymap(FORWARD_KEYS, &addr);
+        let context = context();
+
+        // Only the header is decoded, so the truncated tail is left to the upstream
+        let body = gzip(
+            b"{\"event_id\":\"9ec79c33ec9942ab8353589fcb2e04dc\"}\n{\"type\":\"event\"}\n{}\n",
+        );
+        let truncated = body.slice(..body.len() - 10);
+        let req = envelope_request()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Full::new(truncated.clone()))
+            .unwrap();
+        let response = forward(req, &keymap, &context).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let requests = received(&mut receiver);
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].2, truncated);
+        assert_eq!(context.metrics.decode_skipped.get(), 1);
+
+        // Headers that can't be decoded are rejected
+        let req = envelope_request()
+            .header(CONTENT_ENCODING, "gzip")
+            .body(Full::new(body.slice(..12)))
+            .unwrap();
+        let response = forward(req, &keymap, &context).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(received(&mut receiver).is_empty());
+        let failures = context
+            .metrics
+            .decode_failures
+            .with_label_values(&["invalid_body"])
+            .get();
+        assert_eq!(failures, 1);
+        assert_eq!(context.metrics.decode_skipped.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn primary_responds_before_retries() {
+        let (addr, mut receiver) = upstream(StatusCode::SERVICE_UNAVAILABLE).await;
+        let keymap = keymap(
+            r#"
+- inbound: http://aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa@localhost/1
+  outbound:
+    - dsn: http://bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb@sentry.io/2
+      primary: true
+      retry:
+        max_attempts: 2
+        base_backoff: 500
+        jitter: false
+"#,
+            &addr,
+        );
+        let context = context();
+
+        let req = envelope_request()
+            .body(Full::new(Bytes::from_static(
+                b"{}\n{\"type\":\"event\"}\n{}\n",
+            )))
+            .unwrap();
+        let response = forward(req, &keymap, &context).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(received(&mut receiver).len(), 1);
+
+        // The retry is sent after the client has its response
+        context.tasks.close();
+        context.tasks.wait().await;
+        assert_eq!(received(&mut receiver).len(), 1);
+        let failed = context
+            .metrics
+            .outbound_deliveries
+            .with_label_values(&[&addr, "failed"])
+            .get();
+        assert_eq!(failed, 1);
+    }
+
+    #[tokio::test]
+    async fn primary_connection_errors_respond_before_retries() {
+        // Nothing listens on the address once the listener is dropped
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let keymap = keymap(
+            r#"
+- inbound: http://aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa@localhost/1
+  outbound:
+    - dsn: http://bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb@sentry.io/2
+      primary: true
+      retry:
+        max_attempts: 3
+        base_backoff: 5000
+        jitter: false
+"#,
+            &addr,
+        );
+        let context = context();
+
+        let req = envelope_request()
+            .body(Full::new(Bytes::from_static(
+                b"{}\n{\"type\":\"event\"}\n{}\n",
+            )))
+            .unwrap();
+        let response =
+            tokio::time::timeout(Duration::from_secs(2), forward(req, &keymap, &context))
+                .await
+                .expect("the client waited for retries");
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        context.abandon.cancel();
+    }
+
+    #[tokio::test]
+    async fn cors_headers_on_errors() {
+        use http_body_util::StreamBody;
+        use hyper::body::Frame;
+        use hyper::header::{ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN, VARY};
+
+        let (addr, _receiver) = upstream(StatusCode::OK).await;
+        let keymap = keymap(
+            r#"
+- inbound: http://aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa@localhost/1
+  outbound:
+    - http://bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb@sentry.io/2
+  allowed_origins: ["*.acme.org"]
+"#,
+            &addr,
+        );
+
+        let req = envelope_request()
+            .header(ORIGIN, "https://evil.com")
+            .body(Full::new(Bytes::from_static(b"{}\n")))
+            .unwrap();
+        let response = handle_request(req, keymap.clone(), context())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.headers()[VARY], "origin");
+        assert!(!response.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+
+        // Requests with bodies that can't be read still get CORS headers
+        let chunks: Vec<std::result::Result<Frame<Bytes>, std::io::Error>> = vec![
+            Ok(Frame::data(Bytes::from_static(b"{}\n"))),
+            Err(std::io::ErrorKind::ConnectionReset.into()),
+        ];
+        let req = envelope_request()
+            .header(ORIGIN, "https://app.acme.org:8443")
+            .body(StreamBody::new(futures::stream::iter(chunks)))
+            .unwrap();
+        let response = handle_request(req, keymap, context()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.acme.org:8443"
+        );
+        assert_eq!(response.headers()[VARY], "origin");
+    }
 }
@@ -0,0 +1,473 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Request, Uri};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use tokio_util::sync::CancellationToken;
+
+use crate::client::OutboundClient;
+use crate::config::SpoolConfig;
+use crate::dsn;
+use crate::retry;
+
+/// File extension of completely written spool entries.
+const ENTRY_EXTENSION: &str = "req";
+
+/// File extension of entries that are being written.
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Spooled requests are stored as a JSON header line followed by the raw request body.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct EntryHeader {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+    /// Unix timestamp after which the entry is discarded instead of replayed.
+    expires: Option<u64>,
+}
+
+/// A write-ahead log of outbound requests that could not be delivered.
+/// Each outbound DSN has a directory in the spool, and each request is a file in that directory.
+/// Entries are written to a temporary file and renamed into place so that partial
+/// writes are never replayed.
+#[derive(Debug)]
+pub struct Spool {
+    root: PathBuf,
+    sequence: AtomicU64,
+    /// Writes to a directory are serialized so that concurrent requests can't
+    /// exceed its `max_size`.
+    locks: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl Spool {
+    /// Create a spool rooted at `root`, creating the directory if necessary.
+    /// Temporary files left behind by writes that never completed are removed.
+    pub fn new(root: &Path) -> io::Result<Spool> {
+        std::fs::create_dir_all(root)?;
+        for dir in std::fs::read_dir(root)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in std::fs::read_dir(dir.path())? {
+                let path = file?.path();
+                if path.extension().is_some_and(|ext| ext == TEMP_EXTENSION) {
+                    warn!("Removing incomplete spool entry {0}", path.display());
+                    std::fs::remove_file(&path)?;
+                }
+            }
+        }
+
+        Ok(Spool {
+            root: root.to_path_buf(),
+            sequence: AtomicU64::new(0),
+            locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Get the lock for writes to a spool directory.
+    fn lock(&self, dir: &Path) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self.locks.lock().unwrap();
+        locks.entry(dir.to_path_buf()).or_default().clone()
+    }
+
+    /// Store a request for `outbound` so that it can be replayed later.
+    pub async fn push(
+        &self,
+        outbound: &dsn::Dsn,
+        settings: &SpoolConfig,
+        req: Request<Full<Bytes>>,
+    ) -> io::Result<()> {
+        let (parts, body) = req.into_parts();
+        let body = match body.collect().await {
+            Ok(collected) => collected.to_bytes(),
+            Err(never) => match never {},
+        };
+        let headers = parts
+            .headers
+            .iter()
+            .map(|(key, value)| {
+                (
+                    key.to_string(),
+                    String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                )
+            })
+            .collect();
+        let header = EntryHeader {
+            method: parts.method.to_string(),
+            uri: parts.uri.to_string(),
+            headers,
+            expires: settings.max_age.map(|age| unix_now() + age),
+        };
+        let mut data = serde_json::to_vec(&header)?;
+        data.push(b'\n');
+        data.extend_from_slice(&body);
+
+        let dir = self.root.join(dir_name(outbound));
+        let lock = self.lock(&dir);
+        let _guard = lock.lock().await;
+        fs::create_dir_all(&dir).await?;
+        if let Some(max_size) = settings.max_size {
+            if data.len() as u64 > max_size {
+                warn!(
+                    "Request for {0} is larger than the spool max_size, dropping it",
+                    outbound.host
+                );
+                return Ok(());
+            }
+            make_room(&dir, max_size - data.len() as u64).await?;
+        }
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{millis:016}-{sequence:010}");
+        let tmp_path = dir.join(format!("{name}.{TEMP_EXTENSION}"));
+
+        let mut file = fs::File::create(&tmp_path).await?;
+        file.write_all(&data).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp_path, dir.join(format!("{name}.{ENTRY_EXTENSION}"))).await?;
+
+        Ok(())
+    }
+
+    /// Attempt to deliver every spooled request.
+    pub async fn replay(&self, client: &OutboundClient) -> io::Result<()> {
+        let mut dirs = fs::read_dir(&self.root).await?;
+        while let Some(dir) = dirs.next_entry().await? {
+            if dir.file_type().await?.is_dir() {
+                self.replay_dir(client, &dir.path()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Replay the requests for a single outbound DSN, oldest first.
+    /// Replay stops at the first failure as the upstream is likely still unavailable.
+    async fn replay_dir(&self, client: &OutboundClient, dir: &Path) -> io::Result<()> {
+        for (path, _) in entries(dir).await? {
+            let data = match fs::read(&path).await {
+                Ok(data) => Bytes::from(data),
+                // Entries can be discarded by a concurrent push that makes room
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let (header, body) = match decode_entry(&data) {
+                Some(entry) => entry,
+                None => {
+                    warn!("Discarding unreadable spool entry {0}", path.display());
+                    remove_entry(&path).await?;
+                    continue;
+                }
+            };
+            if header.expires.is_some_and(|expires| expires < unix_now()) {
+                debug!("Discarding expired spool entry {0}", path.display());
+                remove_entry(&path).await?;
+                continue;
+            }
+            let request = match build_request(&header, body) {
+                Some(r) => r,
+                None => {
+                    warn!("Discarding invalid spool entry {0}", path.display());
+                    remove_entry(&path).await?;
+                    continue;
+                }
+            };
+            match client.send(request).await {
+                Ok(response) if !retry::is_retryable(response.status()) => {
+                    debug!("Replayed spooled request to {0}", header.uri);
+                    remove_entry(&path).await?;
+                }
+                Ok(response) => {
+                    debug!(
+                        "Upstream for {0} is still failing with {1}",
+                        header.uri,
+                        response.status()
+                    );
+                    break;
+                }
+                Err(e) => {
+                    debug!("Upstream for {0} is still unavailable: {1}", header.uri, e);
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replay the spool every `interval` until `shutdown` is cancelled.
+pub async fn run(
+    spool: Arc<Spool>,
+    client: OutboundClient,
+    interval: Duration,
+    shutdown: CancellationToken,
+) {
+    info!("Replaying spool every {0} seconds", interval.as_secs());
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        // Replays that have started are finished during shutdown
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+        if let Err(e) = spool.replay(&client).await {
+            warn!("Could not replay spool: {0}", e);
+        }
+    }
+}
+
+/// Get the spool directory name for an outbound DSN.
+fn dir_name(outbound: &dsn::Dsn) -> String {
+    let name = format!(
+        "{0}-{1}-{2}",
+        outbound.host, outbound.project_id, outbound.public_key
+    );
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// List the completed entries in a spool directory with their sizes, oldest first.
+async fn entries(dir: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    files(dir, ENTRY_EXTENSION).await
+}
+
+/// List the files in a spool directory with an extension and their sizes, oldest first.
+async fn files(dir: &Path, extension: &str) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut found = Vec::new();
+    let mut listing = fs::read_dir(dir).await?;
+    while let Some(entry) = listing.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_some_and(|ext| ext == extension) {
+            found.push((path, entry.metadata().await?.len()));
+        }
+    }
+    found.sort();
+
+    Ok(found)
+}
+
+/// Remove the oldest entries in `dir` until it holds at most `limit` bytes.
+/// Temporary files count towards the size of the directory, but are not removed.
+async fn make_room(dir: &Path, limit: u64) -> io::Result<()> {
+    let found = entries(dir).await?;
+    let temp = files(dir, TEMP_EXTENSION).await?;
+    let mut total: u64 = found.iter().chain(temp.iter()).map(|(_, size)| size).sum();
+    for (path, size) in found {
+        if total <= limit {
+            break;
+        }
+        warn!("Spool is full, discarding oldest entry {0}", path.display());
+        remove_entry(&path).await?;
+        total -= size;
+    }
+    Ok(())
+}
+
+/// Remove a spool entry. Entries that were already removed are ignored.
+async fn remove_entry(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn decode_entry(data: &Bytes) -> Option<(EntryHeader, Bytes)> {
+    let newline = data.iter().position(|&b| b == b'\n')?;
+    let header = serde_json::from_slice(&data[..newline]).ok()?;
+
+    Some((header, data.slice(newline + 1..)))
+}
+
+fn build_request(header: &EntryHeader, body: Bytes) -> Option<Request<Full<Bytes>>> {
+    let uri: Uri = header.uri.parse().ok()?;
+    let mut builder = Request::builder().method(header.method.as_str()).uri(uri);
+    for (key, value) in header.headers.iter() {
+        builder = builder.header(key, value);
+    }
+    builder.body(Full::new(body)).ok()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::TempDir;
+
+    /// Create a spool in a temporary directory, which is removed with the `TempDir`.
+    fn temp_spool(name: &str) -> (TempDir, Spool) {
+        let root = TempDir::new(&format!("spool-{name}"));
+        let spool = Spool::new(root.path()).unwrap();
+
+        (root, spool)
+    }
+
+    fn make_request(body: &'static str) -> Request<Full<Bytes>> {
+        Request::builder()
+            .method("POST")
+            .uri("https://o123.ingest.sentry.io/api/6789/envelope/")
+            .header("X-Sentry-Auth", "sentry_key=outbound")
+            .body(Full::new(Bytes::from(body)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn push_writes_entry() {
+        let (_root, spool) = temp_spool("push");
+        let outbound: dsn::Dsn = "https://outbound@o123.ingest.sentry.io/6789"
+            .parse()
+            .unwrap();
+        let settings = SpoolConfig {
+            max_size: None,
+            max_age: Some(60),
+        };
+        spool
+            .push(&outbound, &settings, make_request("some body"))
+            .await
+            .unwrap();
+
+        let dir = spool.root.join(dir_name(&outbound));
+        let found = entries(&dir).await.unwrap();
+        assert_eq!(found.len(), 1);
+
+        let data = Bytes::from(std::fs::read(&found[0].0).unwrap());
+        let (header, body) = decode_entry(&data).unwrap();
+        assert_eq!(header.method, "POST");
+        assert_eq!(
+            header.uri,
+            "https://o123.ingest.sentry.io/api/6789/envelope/"
+        );
+        assert_eq!(
+            header.headers,
+            vec![(
+                "x-sentry-auth".to_string(),
+                "sentry_key=outbound".to_string()
+            )]
+        );
+        assert!(header.expires.is_some());
+        assert_eq!(body, "some body");
+
+        let request = build_request(&header, body).unwrap();
+        assert_eq!(
+            request.uri(),
+            "https://o123.ingest.sentry.io/api/6789/envelope/"
+        );
+        assert_eq!(request.headers()["X-Sentry-Auth"], "sentry_key=outbound");
+    }
+
+    #[tokio::test]
+    async fn push_discards_oldest_when_full() {
+        let (_root, spool) = temp_spool("full");
+        let outbound: dsn::Dsn = "https://outbound@o123.ingest.sentry.io/6789"
+            .parse()
+            .unwrap();
+        let settings = SpoolConfig {
+            max_size: Some(400),
+            max_age: None,
+        };
+        for body in ["first", "second", "third"] {
+            spool
+                .push(&outbound, &settings, make_request(body))
+                .await
+                .unwrap();
+        }
+
+        let dir = spool.root.join(dir_name(&outbound));
+        let found = entries(&dir).await.unwrap();
+        let total: u64 = found.iter().map(|(_, size)| size).sum();
+        assert!(total <= 400);
+        assert!(found.len() < 3);
+
+        let newest = Bytes::from(std::fs::read(&found.last().unwrap().0).unwrap());
+        let (_, body) = decode_entry(&newest).unwrap();
+        assert_eq!(body, "third");
+    }
+
+    #[tokio::test]
+    async fn concurrent_pushes_respect_max_size() {
+        let (_root, spool) = temp_spool("concurrent");
+        let outbound: dsn::Dsn = "https://outbound@o123.ingest.sentry.io/6789"
+            .parse()
+            .unwrap();
+        let settings = SpoolConfig {
+            max_size: Some(400),
+            max_age: None,
+        };
+        let pushes = (0..10).map(|_| spool.push(&outbound, &settings, make_request("body")));
+        for res in futures::future::join_all(pushes).await {
+            res.unwrap();
+        }
+
+        let dir = spool.root.join(dir_name(&outbound));
+        let found = entries(&dir).await.unwrap();
+        let total: u64 = found.iter().map(|(_, size)| size).sum();
+        assert!(total <= 400);
+        assert!(!found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn new_removes_temp_files() {
+        let root = TempDir::new("spool-temp");
+        let dir = root.path().join("o123.ingest.sentry.io-6789-outbound");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("0000000000000001-0000000000.tmp"), "partial").unwrap();
+        std::fs::write(dir.join("0000000000000001-0000000001.req"), "{}\n").unwrap();
+
+        Spool::new(root.path()).unwrap();
+        assert!(files(&dir, TEMP_EXTENSION).await.unwrap().is_empty());
+        assert_eq!(entries(&dir).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn remove_entry_ignores_removed_files() {
+        let (_root, spool) = temp_spool("removed");
+        let outbound: dsn::Dsn = "https://outbound@o123.ingest.sentry.io/6789"
+            .parse()
+            .unwrap();
+        let settings = SpoolConfig {
+            max_size: None,
+            max_age: None,
+        };
+        spool
+            .push(&outbound, &settings, make_request("body"))
+            .await
+            .unwrap();
+        let dir = spool.root.join(dir_name(&outbound));
+        let (path, _) = entries(&dir).await.unwrap().remove(0);
+        std::fs::remove_file(&path).unwrap();
+        assert!(remove_entry(&path).await.is_ok());
+    }
+
+    #[test]
+    fn dir_name_sanitized() {
+        let outbound: dsn::Dsn = "https://outbound@o123.ingest.sentry.io/6789"
+            .parse()
+            .unwrap();
+        assert_eq!(dir_name(&outbound), "o123.ingest.sentry.io-6789-outbound");
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::config;
+use crate::dsn;
+use crate::tls;
+
+/// Load and validate the configuration file at `path` and print a table of its keys.
+/// Returns false when the configuration has any problems.
+pub fn run(path: &Path) -> bool {
+    let configdata = match config::load_config(path) {
+        Ok(configdata) => configdata,
+        Err(e) => {
+            eprintln!("Invalid configuration file {0}: {1}", path.display(), e);
+            return false;
+        }
+    };
+    let mut valid = true;
+    if configdata.port.is_none() {
+        eprintln!("Missing required configuration `port`");
+        valid = false;
+    }
+    if let Some(tls_config) = &configdata.tls {
+        if let Err(e) = tls::load_server_config(tls_config) {
+            eprintln!("Invalid TLS configuration: {0}", e);
+            valid = false;
+        }
+    }
+    let keymap = match dsn::make_key_map(configdata.keys, configdata.retry.as_ref()) {
+        Ok(keymap) => keymap,
+        Err(e) => {
+            eprintln!("Invalid configuration file {0}: {1}", path.display(), e);
+            return false;
+        }
+    };
+    if configdata.spool_path.is_none() && keymap.values().any(|k| k.spool.is_some()) {
+        eprintln!("Keys have spool settings but `spool_path` is not set");
+        valid = false;
+    }
+    print!("{0}", key_table(&keymap));
+
+    valid
+}
+
+/// Format a table of inbound public keys and the outbound DSNs they are mirrored to.
+fn key_table(keymap: &HashMap<String, dsn::DsnKeyRing>) -> String {
+    let mut rows = vec![[
+        "INBOUND".to_string(),
+        "OUTBOUND HOST".to_string(),
+        "PROJECT".to_string(),
+        "PUBLIC KEY".to_string(),
+    ]];
+    let mut inbound_keys: Vec<_> = keymap.keys().collect();
+    inbound_keys.sort();
+    for inbound_key in inbound_keys {
+        let keyring = &keymap[inbound_key];
+        for (i, outbound) in keyring.outbound.iter().enumerate() {
+            let inbound = if i == 0 {
+                inbound_key.to_string()
+            } else {
+                String::new()
+            };
+            let mut project = outbound.dsn.project_id.clone();
+            if outbound.primary {
+                project.push_str(" (primary)");
+            }
+            rows.push([
+                inbound,
+                outbound.dsn.host.clone(),
+                project,
+                outbound.dsn.public_key.clone(),
+            ]);
+        }
+    }
+
+    let mut widths = [0; 4];
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row.iter()) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let mut table = String::new();
+    for row in rows {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{KeyRing, OutboundKey, OutboundOptions};
+
+    #[test]
+    fn key_table_rows() {
+        let keys = vec![KeyRing {
+            inbound: Some("https://abcdef@sentry.io/1234".to_string()),
+            outbound: vec![
+                Some("https://ghijkl@o1.ingest.sentry.io/567".into()),
+                Some(OutboundKey::Options(Box::new(OutboundOptions {
+                    dsn: "https://mnopq@o2.ingest.de.sentry.io/890".to_string(),
+                    primary: true,
+                    retry: None,
+                    ..Default::default()
+                }))),
+            ],
+            spool: None,
+            allowed_origins: None,
+        }];
+        let keymap = dsn::make_key_map(keys, None).unwrap();
+        let table = key_table(&keymap);
+        let expected = "\
+INBOUND  OUTBOUND HOST           PROJECT        PUBLIC KEY
+abcdef   o1.ingest.sentry.io     567            ghijkl
+         o2.ingest.de.sentry.io  890 (primary)  mnopq
+";
+        assert_eq!(table, expected);
+    }
+}